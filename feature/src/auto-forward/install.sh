@@ -2,6 +2,8 @@
 USERNAME="${USERNAME:-"${_REMOTE_USER:-"automatic"}"}"
 PORT="${PORT:-"28258"}"
 VERSION="${VERSION:-"latest"}"
+ENCRYPT="${ENCRYPT:-"false"}"
+SOCKET="${SOCKET:-""}"
 
 
 #Functions
@@ -53,6 +55,11 @@ fi
 # Setting up the local Feature Directory
 mkdir "${PROJECT_DIR}"
 
+# The control socket of the agent, only the remote user may enter the directory.
+mkdir "${PROJECT_DIR}/run"
+chown "${USERNAME}" "${PROJECT_DIR}/run"
+chmod 700 "${PROJECT_DIR}/run"
+
 tee -a "${PROJECT_DIR}/entrypoint.sh" > /dev/null \
 << EOF
 #!/bin/bash
@@ -60,6 +67,8 @@ tee -a "${PROJECT_DIR}/entrypoint.sh" > /dev/null \
 set -e
 
 PORT=${PORT}
+ENCRYPT=${ENCRYPT}
+SOCKET=${SOCKET}
 
 EOF
 
@@ -72,6 +81,22 @@ whoami
 
 echo "${PORT}"
 
+# The Host only accepts Containers knowing its token, which is given at runtime and never stored
+# in the image, e.g. as a file mounted from the Host.
+if [ -z "${AUTO_FORWARD_TOKEN}" ] && [ -r "${AUTO_FORWARD_TOKEN_FILE}" ]; then
+    export AUTO_FORWARD_TOKEN="$(cat "${AUTO_FORWARD_TOKEN_FILE}")"
+fi
+if [ -z "${AUTO_FORWARD_ENCRYPT}" ]; then
+    export AUTO_FORWARD_ENCRYPT="${ENCRYPT}"
+fi
+if [ -z "${AUTO_FORWARD_SOCKET}" ] && [ -n "${SOCKET}" ]; then
+    export AUTO_FORWARD_SOCKET="${SOCKET}"
+fi
+# The entrypoint may run as root, the subcommands run as the remote user. Only the remote user
+# may enter the directory of the socket, so the socket itself is open to everyone.
+export AUTO_FORWARD_CONTROL="${AUTO_FORWARD_CONTROL:-/usr/local/share/auto_forward/run/agent.sock}"
+export AUTO_FORWARD_CONTROL_MODE="${AUTO_FORWARD_CONTROL_MODE:-0o666}"
+
 /usr/local/share/auto_forward/container "${PORT}"&
 
 echo "Container is Listening!"
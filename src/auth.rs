@@ -0,0 +1,163 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::Path;
+
+/// Random bytes of a generated token and of a challenge.
+const RANDOM_BYTES: usize = 32;
+
+/// A new random token, hex encoded.
+pub fn generate_token() -> Result<String, String> {
+    let mut bytes = [0; RANDOM_BYTES];
+    getrandom::getrandom(&mut bytes)
+        .map_err(|err| format!("ERROR: Unable to generate random bytes\n{err}"))?;
+    Ok(encode_hex(&bytes))
+}
+
+/// The token stored at `path`, a new one is generated and stored if the file does not exist.
+///
+/// Only the owner may read a generated file, as the token grants access to the Host. An existing
+/// file is refused unless it belongs to this user and nobody else may read it, as it may be in a
+/// directory shared with other users.
+pub fn load_token(path: &Path) -> Result<String, String> {
+    if path.exists() {
+        #[cfg(unix)]
+        check_private(path, "token")?;
+        let token = fs::read_to_string(path)
+            .map_err(|err| format!("ERROR: Unable to read the token {}\n{err}", path.display()))?;
+        return match token.trim() {
+            "" => Err(format!("ERROR: The token {} is empty", path.display())),
+            token => Ok(token.to_string()),
+        };
+    }
+    let token = generate_token()?;
+    let stored = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| {
+            let mut options = OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            options.mode(0o600);
+            options.open(path)
+        })
+        .and_then(|mut file| writeln!(file, "{token}"));
+    match stored {
+        Ok(()) => Ok(token),
+        Err(err) => Err(format!(
+            "ERROR: Unable to store the token {}\n{err}",
+            path.display()
+        )),
+    }
+}
+
+/// Fails unless the `name` at `path` belongs to this user and no other user may access it, e.g.
+/// in a directory shared with other users.
+#[cfg(unix)]
+pub(crate) fn check_private(path: &Path, name: &str) -> Result<(), String> {
+    let metadata = fs::symlink_metadata(path)
+        .map_err(|err| format!("ERROR: Unable to read the {name} {}\n{err}", path.display()))?;
+    // SAFETY: geteuid has no arguments and always succeeds.
+    let uid = unsafe { libc::geteuid() };
+    if metadata.uid() != uid {
+        return Err(format!(
+            "ERROR: The {name} {} does not belong to this user",
+            path.display()
+        ));
+    }
+    // Symbolic links are always accessible, they are refused as well.
+    if metadata.mode() & 0o077 != 0 {
+        return Err(format!(
+            "ERROR: The {name} {0} may be accessed by other users, restrict it with chmod go-rwx {0}",
+            path.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Proves the knowledge of `token` for `challenge`, without revealing the token.
+pub fn respond(token: &str, challenge: &str) -> String {
+    let mut mac = mac(token);
+    mac.update(challenge.as_bytes());
+    encode_hex(&mac.finalize().into_bytes())
+}
+
+/// Whether `response` was created from `challenge` with `token`, compared in constant time.
+pub fn verify(token: &str, challenge: &str, response: &str) -> bool {
+    let response = match decode_hex(response) {
+        Some(response) => response,
+        None => return false,
+    };
+    let mut mac = mac(token);
+    mac.update(challenge.as_bytes());
+    mac.verify_slice(&response).is_ok()
+}
+
+fn mac(token: &str) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(token.as_bytes()).expect("HMAC accepts keys of any size")
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test_auth {
+    use super::*;
+
+    #[test]
+    fn respond_to_challenge() {
+        let challenge = generate_token().unwrap();
+        let response = respond("secret", &challenge);
+        assert!(verify("secret", &challenge, &response));
+        assert!(!verify("guess", &challenge, &response));
+        assert!(!verify("secret", "other", &response));
+        assert!(!verify("secret", &challenge, "zz"));
+        assert!(!verify("secret", &challenge, ""));
+    }
+
+    #[test]
+    fn store_generated_token() {
+        let path = std::env::temp_dir()
+            .join(format!("auto_forward-token-{}", std::process::id()))
+            .join("token");
+        let token = load_token(&path).unwrap();
+        assert_eq!(token, load_token(&path).unwrap());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(0o600, mode & 0o777);
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(load_token(&path).is_err());
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        fs::write(&path, "\n").unwrap();
+        assert!(load_token(&path).is_err());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn random_tokens() {
+        let token = generate_token().unwrap();
+        assert_eq!(2 * RANDOM_BYTES, token.len());
+        assert_ne!(token, generate_token().unwrap());
+        assert_eq!(
+            Some(vec![0x0f, 0xa0]),
+            decode_hex(&encode_hex(&[0x0f, 0xa0]))
+        );
+    }
+}
@@ -1,225 +1,975 @@
 use auto_forward::*;
 use std::collections::HashMap;
 use std::env;
-use std::io::Read;
-use std::io::Write;
-use std::net::TcpStream;
-use std::process::Command;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::exit;
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::channel;
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-#[derive(PartialEq, Clone)]
-struct ListenPort {
-    port: u16,
-    ip: String,
-    protocol: Protocol,
-    app: String,
-}
-
-fn detect_open_port() -> Vec<ListenPort> {
-    // lsof -i -P -n
-    let output = Command::new("lsof")
-        .arg("-i")
-        .arg("-P")
-        .arg("-n")
-        .output()
-        .expect("ERROR: unable to search for ports");
-    let stdout = str::from_utf8(&output.stdout).expect("ERROR: Unable to parse stdout!");
-    let mut results = stdout.split('\n').collect::<Vec<&str>>();
-    let header = results
-        .remove(0)
-        .split(' ')
-        .map(|s| s.trim())
-        .filter(|s| !s.is_empty())
-        .collect::<Vec<&str>>();
-    let mut table = results
-        .into_iter()
-        .map(|row| {
-            row.split(' ')
-                .map(|s| s.trim())
-                .filter(|s| !s.is_empty())
-                .collect::<Vec<&str>>()
-        })
-        .filter(|row| row.len() == header.len() + 1)
-        .collect::<Vec<Vec<&str>>>();
-    table.retain(|r| match r.last() {
-        Some(l) => *l == "(LISTEN)",
-        None => false,
-    });
-    let mut port_list: Vec<ListenPort> = Vec::new();
-    for row in table {
-        let port_str: &str = match row.get(header.len() - 1) {
-            Some(port) => port.split(':').last().unwrap_or(port),
-            None => continue,
-        };
-        let port = match port_str.parse::<u16>() {
-            Ok(port) => port,
-            Err(_) => continue,
-        };
-        let port_str = row.get(header.len() - 1).unwrap_or(&"a");
-        let mut ip: String = match row.get(header.len() - 1) {
-            Some(port) => port
-                .split(':')
-                .take(port_str.split(':').count() - 1)
-                .collect::<Vec<&str>>()
-                .join(":"),
-            None => continue,
-        };
-        if ip == "*" {
-            ip = "localhost".to_string();
-        }
-        let proto = match Protocol::decode(
-            row[header
-                .iter()
-                .position(|x| *x == "NODE")
-                .unwrap_or(header.len() - 2)],
-        ) {
-            Ok(protocol) => protocol,
-            Err(_) => continue,
-        };
-        let app = match row.first() {
-            Some(app) => app.to_string(),
-            None => "Unkown".to_string(),
-        };
-        let item = ListenPort {
-            port,
-            ip,
-            protocol: proto,
-            app,
-        };
-        port_list.push(item);
-    }
-    port_list
-}
+/// Delay before the first reconnect, doubled after every failed attempt.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Upper bound of the delay between two reconnects.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
 
-fn request_new_port(port: &ListenPort) -> Message {
+/// Set once the agent was asked to stop, so a lost connection is not reestablished.
+static CLOSING: AtomicBool = AtomicBool::new(false);
+
+/// Set while the Host of the current session accepts reverse streams.
+static REVERSE: AtomicBool = AtomicBool::new(false);
+
+/// Folder the devcontainer CLI and VS Code mount the workspaces to.
+const WORKSPACES: &str = "/workspaces";
+
+/// Time `container forward` waits for the answer of the Host.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The Session is encrypted with the token of the Host, so the Container has to know it.
+const ENCRYPT_WITHOUT_TOKEN: &str =
+    "ERROR: Encryption requires the token of the Host, set AUTO_FORWARD_TOKEN to the output of `host token`";
+
+/// The only option without a value, the agent talks to the Host over stdin and stdout.
+const STDIO: &str = "--stdio";
+
+/// Subcommands, which talk to the running agent over its control socket.
+const COMMANDS: &str = "Commands of the running agent:
+  container forward <port>[:<host port>]  Forwards a port, whether it is listening or not, e.g. 8080:9090 or 53/udp
+  container unforward <port>              Stops forwarding a port
+  container ls                            Requested ports and the answers of the Host
+The agent itself talks to the Host over stdin and stdout with container --stdio, e.g. in host --spawn.";
+
+/// Ports forwarded with `container forward` and the host port asked for.
+type ManualPorts = Arc<RwLock<HashMap<(u16, Protocol), Option<u16>>>>;
+
+/// The last answer of the Host to every requested port.
+type Replies = Arc<RwLock<HashMap<(u16, Protocol), PortReply>>>;
+
+/// Channels of the open streams of the current session, by their id.
+type Streams = Arc<RwLock<HashMap<u32, Sender<Message>>>>;
+
+fn request_new_port(
+    port: &ListenPort,
+    devcontainer: &DevContainer,
+    host_port: Option<u16>,
+) -> Message {
     let function = match port.protocol {
         Protocol::TCP => Function::CreateTcp,
         Protocol::UDP => Function::CreateUdp,
     };
-    create_message(port.port, function, port.app.clone().into_bytes())
+    let request = PortRequest {
+        host_port,
+        ..PortRequest::new(port, devcontainer.attributes(port))
+    };
+    create_message(port.port, function, request.encode())
 }
 
-fn send_close_port(port: ListenPort) {
-    println!("INFO: Closing Port {port}", port = port.port);
+fn request_close_port(port: &ListenPort) -> Message {
+    let function = match port.protocol {
+        Protocol::TCP => Function::CloseTcp,
+        Protocol::UDP => Function::CloseUdp,
+    };
+    create_message(port.port, function, Vec::new())
+}
+
+fn send_close_port(sender: &Sender<Message>, port: ListenPort) {
+    info!("Closing Port {port}", port = port.port);
+    // Without a connection the Host already closed the port together with the session.
+    let _ = sender.send(request_close_port(&port));
 }
 
-fn port_manager(sender: Sender<Message>, port_register: Arc<RwLock<HashMap<u16, ListenPort>>>) {
+fn port_manager(
+    session_sender: Arc<Mutex<Sender<Message>>>,
+    port_register: Arc<RwLock<HashMap<(u16, Protocol), ListenPort>>>,
+    manual: ManualPorts,
+    reverse: Vec<u16>,
+    mut detector: Box<dyn PortDetector + Send>,
+    rules: Rules,
+    devcontainer: DevContainer,
+) {
+    let mut differ = PortDiffer::new();
+    for port in &devcontainer.forward_ports {
+        info!(
+            "Forwarding Port {} of {}, listed in forwardPorts",
+            port.port, port.ip
+        );
+        let _ = session_sender
+            .lock()
+            .unwrap()
+            .send(request_new_port(port, &devcontainer, None));
+        port_register
+            .write()
+            .unwrap()
+            .insert((port.port, port.protocol.clone()), port.clone());
+    }
     loop {
-        let new_list = detect_open_port();
-        for port in new_list.clone() {
-            if !port_register.read().unwrap().contains_key(&port.port) {
-                let port1 = port.clone();
-                println!(
-                    "INFO: New Open Port\nPort: {pro:?} {port}\nRunning: {app}",
-                    pro = port1.protocol,
-                    port = port1.port,
-                    app = port1.app
-                );
-                sender.send(request_new_port(&port)).unwrap();
-                port_register.write().unwrap().insert(port.port, port);
+        let events = match detector.detect() {
+            Ok(ports) => differ.update(ports),
+            Err(err) => {
+                error!("ERROR: Unable to search for ports\n{err}");
+                Vec::new()
+            }
+        };
+        // Holding the sender keeps a reconnect from announcing the register in the middle of an update.
+        let sender = session_sender.lock().unwrap();
+        for event in events {
+            match event {
+                // Ports of forwardPorts stay forwarded, whether they are listening or not.
+                PortEvent::Added(port) | PortEvent::Removed(port)
+                    if devcontainer.forwards(&port) => {}
+                // The listeners of the reverse forwarded ports lead back to the Host.
+                PortEvent::Added(port) | PortEvent::Removed(port)
+                    if port.protocol == Protocol::TCP && reverse.contains(&port.port) => {}
+                // Ports of `container forward` stay forwarded until they are unforwarded.
+                PortEvent::Added(port) | PortEvent::Removed(port)
+                    if manual
+                        .read()
+                        .unwrap()
+                        .contains_key(&(port.port, port.protocol.clone())) => {}
+                PortEvent::Added(port) if devcontainer.attributes(&port).ignored() => {
+                    info!(
+                        "Ignoring Port {:?} {} of {}, onAutoForward is ignore",
+                        port.protocol, port.port, port.app
+                    );
+                }
+                PortEvent::Added(port) if !rules.allows(&port) => {
+                    if let Some(rule) = rules.rule(&port) {
+                        info!(
+                            "Ignoring Port {:?} {} of {}, denied by \"{rule}\"",
+                            port.protocol, port.port, port.app
+                        );
+                    }
+                }
+                PortEvent::Added(port) => {
+                    info!(
+                        "New Open Port\nPort: {pro:?} {number}\nRunning: {app}",
+                        pro = port.protocol,
+                        number = port.port,
+                        app = port.app
+                    );
+                    if let Some(label) = &devcontainer.attributes(&port).label {
+                        info!("Label: {label}");
+                    }
+                    // A port requested without a connection is announced after the reconnect.
+                    let _ = sender.send(request_new_port(&port, &devcontainer, None));
+                    port_register
+                        .write()
+                        .unwrap()
+                        .insert((port.port, port.protocol.clone()), port);
+                }
+                PortEvent::Removed(port) => {
+                    let key = (port.port, port.protocol.clone());
+                    // Ignored ports were never requested from the Host.
+                    if port_register.write().unwrap().remove(&key).is_some() {
+                        send_close_port(&sender, port);
+                    }
+                }
             }
         }
-        for (port, listen_port) in port_register.read().unwrap().iter() {
-            if !new_list.contains(listen_port) {
-                send_close_port(listen_port.clone());
-                port_register.write().unwrap().remove(port);
+        drop(sender);
+        thread::sleep(detector.interval());
+    }
+}
+
+/// Connects to the Host and completes the HELLO, retrying with an exponential backoff.
+fn connect_to_host(config: &Config, hello: &Hello) -> Tunnel {
+    let token = config.container.token.as_deref();
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        match connect(config) {
+            Ok(stream) => match open_tunnel(stream, token, config.encrypt) {
+                Ok(mut tunnel) => match send_hello_with_token(&mut tunnel, hello, token) {
+                    Ok(host) => {
+                        info!("Connected to {host}");
+                        REVERSE.store(host.supports("reverse"), Ordering::SeqCst);
+                        return tunnel;
+                    }
+                    Err(err) => error!("{err}"),
+                },
+                Err(err) => error!("{err}"),
+            },
+            Err(err) => error!("Unable to connect to Host\nERROR: {err}"),
+        };
+        if CLOSING.load(Ordering::SeqCst) {
+            exit(0);
+        }
+        info!("Reconnecting in {} seconds", delay.as_secs());
+        thread::sleep(delay);
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Completes the HELLO over stdin and stdout, the Host started the agent and listens on its stdout.
+///
+/// The Host trusts the commands it spawned, so the Session is neither authenticated nor encrypted.
+fn attach_to_host(config: &Config, hello: &Hello) -> Tunnel {
+    let mut tunnel = Tunnel::from(Pipe::stdio());
+    match send_hello_with_token(&mut tunnel, hello, config.container.token.as_deref()) {
+        Ok(host) => {
+            info!("Connected to {host}");
+            REVERSE.store(host.supports("reverse"), Ordering::SeqCst);
+            tunnel
+        }
+        Err(err) => {
+            error!("{err}");
+            if let Ok(control_socket) = config.container.control_socket() {
+                let _ = fs::remove_file(control_socket);
             }
+            exit(1);
         }
-        thread::sleep(Duration::from_secs(5));
     }
 }
 
+/// The Unix socket of the Host if there is one, otherwise its TCP port.
+fn connect(config: &Config) -> io::Result<Tunnel> {
+    match &config.container.socket {
+        Some(path) => UnixStream::connect(path).map(Tunnel::from),
+        None => TcpStream::connect((config.container.host.as_str(), config.port)).map(Tunnel::from),
+    }
+}
 
-fn get_inital_connection(port: u16) -> TcpStream {
-    loop {
-        match TcpStream::connect(format!("host.docker.internal:{port}")) {
-            Ok(stream) => return stream,
+fn open_tunnel(stream: Tunnel, token: Option<&str>, encrypt: bool) -> Result<Tunnel, String> {
+    match (encrypt, token) {
+        (false, _) => Ok(stream),
+        (true, Some(token)) => SecureStream::connect(stream, token).map(Tunnel::from),
+        (true, None) => Err(ENCRYPT_WITHOUT_TOKEN.to_string()),
+    }
+}
+
+/// Makes `sender` the channel of the new session and requests all known ports from the Host again.
+fn announce_ports(
+    session_sender: &Mutex<Sender<Message>>,
+    sender: Sender<Message>,
+    port_register: &RwLock<HashMap<(u16, Protocol), ListenPort>>,
+    manual: &ManualPorts,
+    replies: &Replies,
+    devcontainer: &DevContainer,
+) {
+    let mut current = session_sender.lock().unwrap();
+    // The answers of the previous session do not hold for the new one.
+    replies.write().unwrap().clear();
+    let manual = manual.read().unwrap();
+    for (key, port) in port_register.read().unwrap().iter() {
+        let host_port = manual.get(key).copied().flatten();
+        let _ = sender.send(request_new_port(port, devcontainer, host_port));
+    }
+    *current = sender;
+}
+
+fn service_address(
+    port_register: &RwLock<HashMap<(u16, Protocol), ListenPort>>,
+    port: u16,
+    protocol: Protocol,
+) -> String {
+    match port_register.read().unwrap().get(&(port, protocol)) {
+        Some(service) => format!("{}:{port}", service.ip),
+        None => format!("localhost:{port}"),
+    }
+}
+
+fn open_stream(
+    message: Message,
+    sender: Sender<Message>,
+    port_register: Arc<RwLock<HashMap<(u16, Protocol), ListenPort>>>,
+    streams: Arc<RwLock<HashMap<u32, Sender<Message>>>>,
+) {
+    let port = message.header.port;
+    let id = message.header.stream;
+    let (stream_sender, stream_receiver) = channel();
+    streams.write().unwrap().insert(id, stream_sender.clone());
+    thread::spawn(move || {
+        let address = service_address(&port_register, port, Protocol::TCP);
+        match TcpStream::connect(&address) {
+            Ok(socket) => {
+                forward_tcp_stream(socket, port, id, sender, stream_sender, &stream_receiver)
+            }
             Err(err) => {
-                eprintln!("Unable to connect to Host\nERROR: {err}")
+                error!("ERROR: Unable to connect to Socket {address}\n{err}");
+                let _ = sender.send(create_stream_message(
+                    port,
+                    id,
+                    Function::TcpReset,
+                    Vec::new(),
+                ));
             }
-        };
-        thread::sleep(Duration::from_secs(5));
-    }
+        }
+        streams.write().unwrap().remove(&id);
+    });
+}
+
+fn connect_udp(address: &str) -> Result<UdpSocket, std::io::Error> {
+    let service = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::from(ErrorKind::AddrNotAvailable))?;
+    let local: SocketAddr = match service {
+        SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+        SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(service)?;
+    socket.set_read_timeout(Some(UDP_SWEEP_INTERVAL))?;
+    Ok(socket)
+}
+
+fn open_udp_session(
+    message: Message,
+    sender: Sender<Message>,
+    port_register: Arc<RwLock<HashMap<(u16, Protocol), ListenPort>>>,
+    streams: Arc<RwLock<HashMap<u32, Sender<Message>>>>,
+) {
+    let port = message.header.port;
+    let session = UdpSession::new(message.header.stream);
+    let address = service_address(&port_register, port, Protocol::UDP);
+    let (socket, write_socket) = match connect_udp(&address).and_then(|socket| {
+        let write_socket = socket.try_clone()?;
+        Ok((socket, write_socket))
+    }) {
+        Ok(sockets) => sockets,
+        Err(err) => {
+            error!("ERROR: Unable to connect to Socket {address}\n{err}");
+            return;
+        }
+    };
+    let (session_sender, session_receiver) = channel::<Message>();
+    session_sender.send(message).unwrap();
+    streams
+        .write()
+        .unwrap()
+        .insert(session.stream, session_sender);
+    let write_session = session.clone();
+    thread::spawn(move || {
+        for message in session_receiver.iter() {
+            if let Err(err) = write_socket.send(&message.body) {
+                error!("ERROR: Unable to send Datagram to {address}\n{err}");
+            }
+            write_session.touch();
+        }
+    });
+    thread::spawn(move || {
+        let mut buffer = vec![0; UDP_BUFFER_SIZE];
+        loop {
+            match socket.recv(&mut buffer) {
+                Ok(size) => {
+                    session.touch();
+                    let message = create_stream_message(
+                        port,
+                        session.stream,
+                        Function::Udp,
+                        buffer[..size].to_vec(),
+                    );
+                    if sender.send(message).is_err() {
+                        break;
+                    }
+                }
+                Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    if session.is_idle() {
+                        break;
+                    }
+                }
+                // A previous Datagram was refused by the service, the session stays usable.
+                Err(err) if err.kind() == ErrorKind::ConnectionRefused => {}
+                Err(err) => {
+                    error!(
+                        "ERROR: Unable to read UDP session {}\n{err}",
+                        session.stream
+                    );
+                    break;
+                }
+            }
+        }
+        streams.write().unwrap().remove(&session.stream);
+    });
 }
 
 fn handle_message(
     message: Message,
     sender: Sender<Message>,
-    port_register: Arc<RwLock<HashMap<u16, ListenPort>>>,
+    port_register: Arc<RwLock<HashMap<(u16, Protocol), ListenPort>>>,
+    replies: &Replies,
+    streams: Arc<RwLock<HashMap<u32, Sender<Message>>>>,
 ) {
-    if message.header.function == Function::Tcp {
-        let request = message;
-        thread::spawn(move || {
-            let service = port_register
-                .read()
-                .unwrap()
-                .get(&request.header.port)
+    match message.header.function {
+        Function::TcpOpen => open_stream(message, sender, port_register, streams),
+        Function::Tcp | Function::TcpShutdown | Function::TcpReset => {
+            let (port, id) = (message.header.port, message.header.stream);
+            let unknown = match streams.read().unwrap().get(&id) {
+                Some(stream) => stream.send(message).is_err(),
+                None => message.header.function == Function::Tcp,
+            };
+            if unknown {
+                let _ = sender.send(create_stream_message(
+                    port,
+                    id,
+                    Function::TcpReset,
+                    Vec::new(),
+                ));
+            }
+        }
+        Function::Udp => {
+            let stream = streams.read().unwrap().get(&message.header.stream).cloned();
+            match stream {
+                Some(stream) => {
+                    if let Err(err) = stream.send(message) {
+                        open_udp_session(err.0, sender, port_register, streams);
+                    }
+                }
+                None => open_udp_session(message, sender, port_register, streams),
+            }
+        }
+        Function::CreatedTcp | Function::CreatedUdp => {
+            let protocol = message.header.function.protocol().unwrap();
+            let reply = match PortReply::decode(&message.body) {
+                Ok(reply) => reply,
+                Err(err) => {
+                    error!("{err}");
+                    return;
+                }
+            };
+            match &reply {
+                PortReply::Forwarded(address) => info!(
+                    "Port {protocol:?} {} is forwarded to {address} on the Host",
+                    message.header.port
+                ),
+                PortReply::Refused(reason) => error!(
+                    "ERROR: The Host refused to forward Port {protocol:?} {}\n{reason}",
+                    message.header.port
+                ),
+            }
+            replies
+                .write()
                 .unwrap()
-                .clone();
-            let mut stream = TcpStream::connect(format!("{}:{}", service.ip, request.header.port))
-                .unwrap_or_else(|err| {
-                    panic!(
-                        "Error: Unable to connect to Socket localhost:{}\n{err}",
-                        request.header.port
-                    )
-                });
-            stream.write_all(&request.body).unwrap();
-            let mut buffer = Vec::new();
-            stream.read_to_end(&mut buffer).unwrap();
-            sender
-                .send(create_message(request.header.port, Function::Tcp, buffer))
-                .unwrap();
-        });
-    } else {
-        eprintln!(
+                .insert((message.header.port, protocol), reply);
+        }
+        _ => error!(
             "INFO: This Function is currently not supported {:#?}",
             message.header.function
-        );
+        ),
     }
 }
 
 fn client_read_stream(
-    stream: TcpStream,
+    mut stream: Tunnel,
     sender: Sender<Message>,
-    port_register: Arc<RwLock<HashMap<u16, ListenPort>>>,
+    port_register: Arc<RwLock<HashMap<(u16, Protocol), ListenPort>>>,
+    replies: Replies,
+    streams: Streams,
 ) {
     loop {
-        match read_message(&stream) {
+        match read_message(&mut stream) {
             Ok(message) => match message {
-                Some(message) => handle_message(message, sender.clone(), port_register.clone()),
+                Some(message) if message.header.function == Function::Close => {
+                    info!(
+                        "Host closed the Session {}",
+                        String::from_utf8_lossy(&message.body)
+                    );
+                    break;
+                }
+                Some(message) => handle_message(
+                    message,
+                    sender.clone(),
+                    port_register.clone(),
+                    &replies,
+                    streams.clone(),
+                ),
                 None => {
-                    eprintln!("Socket closed!");
+                    error!("Socket closed!");
                     break;
                 }
             },
-            Err(err) => eprintln!("Something went wrong with the message:\n{err}"),
+            Err(err) if err.is_recoverable() => {
+                error!("ERROR: Ignoring Message of the Host\n{err}")
+            }
+            Err(err @ ProtocolError::OversizedFrame { .. }) => {
+                error!("ERROR: Closing the Session, the Stream is corrupted\n{err}");
+                let reason = err.to_string().into_bytes();
+                let _ = sender.send(create_message(0, Function::Close, reason));
+                break;
+            }
+            Err(err) => {
+                error!("ERROR: Lost the Connection to the Host\n{err}");
+                break;
+            }
         }
     }
+    // Dropping the channels of the open streams resets them, ids are not reused by the next session.
+    streams.write().unwrap().clear();
 }
 
+/// The state the control socket of the agent works on.
+///
+/// Its locks are always taken in the order of the fields, so concurrent clients of the control
+/// socket and the port manager never wait for each other in a circle.
+#[derive(Clone)]
+struct Agent {
+    session_sender: Arc<Mutex<Sender<Message>>>,
+    manual: ManualPorts,
+    replies: Replies,
+    port_register: Arc<RwLock<HashMap<(u16, Protocol), ListenPort>>>,
+    devcontainer: DevContainer,
+}
+
+impl Agent {
+    fn handle(&self, request: AgentRequest) -> ControlResponse {
+        let result = match request {
+            AgentRequest::List => Ok(ControlResponse {
+                ports: Some(self.ports()),
+                ..ControlResponse::ok()
+            }),
+            AgentRequest::Forward {
+                port,
+                protocol,
+                host_port,
+            } => self.forward(port, protocol, host_port),
+            AgentRequest::Unforward { port, protocol } => self.unforward(port, protocol),
+        };
+        result.unwrap_or_else(ControlResponse::error)
+    }
+
+    fn ports(&self) -> Vec<RequestedPort> {
+        let manual = self.manual.read().unwrap();
+        let replies = self.replies.read().unwrap();
+        let mut ports = self
+            .port_register
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(key, port)| {
+                let reply = replies.get(key);
+                RequestedPort {
+                    port: port.port,
+                    protocol: port.protocol.clone(),
+                    app: port.app.clone(),
+                    manual: manual.contains_key(key),
+                    address: match reply {
+                        Some(PortReply::Forwarded(address)) => Some(*address),
+                        _ => None,
+                    },
+                    refused: match reply {
+                        Some(PortReply::Refused(reason)) => Some(reason.clone()),
+                        _ => None,
+                    },
+                }
+            })
+            .collect::<Vec<RequestedPort>>();
+        ports.sort_by_key(|port| (port.port, port.protocol == Protocol::UDP));
+        ports
+    }
+
+    /// Requests the port from the Host and waits for its answer.
+    fn forward(
+        &self,
+        port: u16,
+        protocol: Protocol,
+        host_port: Option<u16>,
+    ) -> Result<ControlResponse, String> {
+        let key = (port, protocol.clone());
+        let detected = {
+            let sender = self.session_sender.lock().unwrap();
+            let mut manual = self.manual.write().unwrap();
+            self.replies.write().unwrap().remove(&key);
+            let mut register = self.port_register.write().unwrap();
+            let detected = register.contains_key(&key);
+            let listen_port = register.entry(key.clone()).or_insert_with(|| ListenPort {
+                port,
+                ip: "localhost".to_string(),
+                protocol: protocol.clone(),
+                app: "manual".to_string(),
+            });
+            manual.insert(key.clone(), host_port);
+            info!("Forwarding Port {protocol:?} {port} on request");
+            // Without a connection the port is requested after the reconnect.
+            let _ = sender.send(request_new_port(listen_port, &self.devcontainer, host_port));
+            detected
+        };
+        let start = Instant::now();
+        while start.elapsed() < REPLY_TIMEOUT {
+            // Cloned, the reply must not be locked while the manual ports are.
+            let reply = self.replies.read().unwrap().get(&key).cloned();
+            match reply {
+                Some(PortReply::Forwarded(address)) => {
+                    return Ok(ControlResponse {
+                        address: Some(address),
+                        ..ControlResponse::ok()
+                    })
+                }
+                Some(PortReply::Refused(reason)) => {
+                    self.manual.write().unwrap().remove(&key);
+                    if !detected {
+                        self.port_register.write().unwrap().remove(&key);
+                    }
+                    return Err(format!(
+                        "ERROR: The Host refused to forward Port {protocol:?} {port}\n{reason}"
+                    ));
+                }
+                None => thread::sleep(Duration::from_millis(50)),
+            }
+        }
+        // Hosts of autoForward 0.0.5 and older never answer, the port is requested anyway.
+        Ok(ControlResponse::ok())
+    }
+
+    fn unforward(&self, port: u16, protocol: Protocol) -> Result<ControlResponse, String> {
+        let key = (port, protocol.clone());
+        let sender = self.session_sender.lock().unwrap();
+        self.manual.write().unwrap().remove(&key);
+        self.replies.write().unwrap().remove(&key);
+        match self.port_register.write().unwrap().remove(&key) {
+            Some(port) => {
+                send_close_port(&sender, port);
+                Ok(ControlResponse::ok())
+            }
+            None => Err(format!("ERROR: Port {protocol:?} {port} is not forwarded")),
+        }
+    }
+}
 
 fn main() {
-    let port = env::args()
-        .nth(1)
-        .unwrap_or("28258".to_string())
-        .parse::<u16>()
-        .unwrap_or(28258);
-    let stream = get_inital_connection(port);
-    let port_register: Arc<RwLock<HashMap<u16, ListenPort>>> =
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    match args.first().map(String::as_str) {
+        Some(command @ ("forward" | "unforward" | "ls")) => run_command(command, &args[1..]),
+        _ => {
+            let stdio = args.iter().any(|arg| arg == STDIO);
+            let args = args
+                .into_iter()
+                .filter(|arg| arg != STDIO)
+                .collect::<Vec<String>>();
+            run_agent(&args, stdio)
+        }
+    }
+}
+
+/// Runs a subcommand against the running agent, found by the control socket of the configuration.
+fn run_command(command: &str, args: &[String]) {
+    let (arguments, options) = split_options(args);
+    let arguments = arguments.iter().map(String::as_str).collect::<Vec<&str>>();
+    let result = Config::load(&options).and_then(|config| {
+        let mut client = ControlClient::connect(&config.container.control_socket()?)?;
+        match (command, arguments.as_slice()) {
+            ("forward", [port]) => forward(&mut client, port),
+            ("unforward", [port]) => unforward(&mut client, port),
+            ("ls", []) => list(&mut client),
+            _ => Err(format!("ERROR: Invalid arguments of {command}\n{COMMANDS}")),
+        }
+    });
+    if let Err(err) = result {
+        error!("{err}");
+        exit(1);
+    }
+}
+
+fn forward(client: &mut ControlClient, port: &str) -> Result<(), String> {
+    let spec = PortSpec::parse(port)?;
+    let response = client.request(&AgentRequest::Forward {
+        port: spec.port,
+        protocol: spec.protocol.clone(),
+        host_port: spec.host_port,
+    })?;
+    match response.address {
+        Some(address) => println!(
+            "Forwarding {:?} Port {} to {address} on the Host",
+            spec.protocol, spec.port
+        ),
+        None => println!(
+            "Requested {:?} Port {}, the Host did not answer yet",
+            spec.protocol, spec.port
+        ),
+    }
+    Ok(())
+}
+
+fn unforward(client: &mut ControlClient, port: &str) -> Result<(), String> {
+    let spec = PortSpec::parse(port)?;
+    client.request(&AgentRequest::Unforward {
+        port: spec.port,
+        protocol: spec.protocol,
+    })?;
+    Ok(())
+}
+
+fn list(client: &mut ControlClient) -> Result<(), String> {
+    let mut rows = vec![["PORT", "PROTOCOL", "APP", "HOST"].map(String::from)];
+    for port in client
+        .request(&AgentRequest::List)?
+        .ports
+        .unwrap_or_default()
+    {
+        let app = match port.manual {
+            true if port.app != "manual" => format!("{} (manual)", port.app),
+            _ => port.app,
+        };
+        let host = match (port.address, port.refused) {
+            (Some(address), _) => address.to_string(),
+            (None, Some(reason)) => format!("refused: {}", reason.replace('\n', " ")),
+            (None, None) => "-".to_string(),
+        };
+        rows.push([
+            port.port.to_string(),
+            format!("{:?}", port.protocol),
+            app,
+            host,
+        ]);
+    }
+    let mut widths = [0; 4];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<String>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+/// Runs the agent, which forwards the ports of the Container until it is stopped.
+///
+/// With `stdio`, the agent has a single Session over stdin and stdout and stops with it.
+fn run_agent(args: &[String], stdio: bool) {
+    set_log_stderr(stdio);
+    let config = match Config::load(args) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{err}");
+            exit(1);
+        }
+    };
+    set_log_level(config.log);
+    // The devcontainer name is optional and only used to tell Containers apart on the Host.
+    let hello = Hello::new(local_hostname(), env::var("DEVCONTAINER_NAME").ok());
+    let port_register: Arc<RwLock<HashMap<(u16, Protocol), ListenPort>>> =
         Arc::new(RwLock::new(HashMap::new()));
-    let (sender, receiver) = channel();
-    let read_stream = stream.try_clone().expect("Unable to clone stream");
-    let write_stream = stream.try_clone().expect("Unable to clone stream");
-    let port_sender = sender.clone();
+    let manual: ManualPorts = Arc::new(RwLock::new(HashMap::new()));
+    let replies: Replies = Arc::new(RwLock::new(HashMap::new()));
+    let streams: Streams = Arc::new(RwLock::new(HashMap::new()));
+    // Every connection gets a channel of its own, so frames of a lost session never reach the next one.
+    let (sender, _) = channel();
+    let session_sender = Arc::new(Mutex::new(sender));
+    if config.encrypt && config.container.token.is_none() && !stdio {
+        error!("{ENCRYPT_WITHOUT_TOKEN}");
+        exit(1);
+    }
+    let control_listener = config
+        .container
+        .control_socket()
+        .and_then(|control_socket| {
+            let listener = bind_control(&control_socket, config.container.control_mode)?;
+            Ok((listener, control_socket))
+        });
+    let (control_listener, control_socket) = match control_listener {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("{err}");
+            exit(1);
+        }
+    };
+    let close_sender = session_sender.clone();
+    let close_socket = control_socket.clone();
+    ctrlc::set_handler(move || {
+        info!("Closing the Session");
+        CLOSING.store(true, Ordering::SeqCst);
+        let _ = fs::remove_file(&close_socket);
+        let close = create_message(0, Function::Close, Vec::new());
+        // Without a connection there is nobody to tell about the Close.
+        if close_sender.lock().unwrap().send(close).is_err() {
+            exit(0);
+        }
+    })
+    .expect("ERROR: Unable to register the shutdown handler");
+    let port_manager_sender = session_sender.clone();
     let port_manager_register = port_register.clone();
-    let port_thread = thread::spawn(move || port_manager(port_sender, port_manager_register));
-    thread::spawn(move || client_read_stream(read_stream, sender, port_register));
-    thread::spawn(|| client_write_stream(write_stream, receiver));
-    port_thread.join().unwrap();
+    let detector = match port_detector(
+        config.container.detector.as_deref(),
+        &config.container.ports,
+        config.container.poll_interval(),
+    ) {
+        Ok(detector) => detector,
+        Err(err) => {
+            error!("{err}");
+            exit(1);
+        }
+    };
+    let rules = match config
+        .container
+        .rules
+        .iter()
+        .map(|rule| Rule::parse(rule))
+        .collect::<Result<Vec<Rule>, String>>()
+    {
+        Ok(rules) => Rules::new(rules),
+        Err(err) => {
+            error!("{err}");
+            exit(1);
+        }
+    };
+    let devcontainer = match config
+        .container
+        .devcontainer
+        .as_ref()
+        .map(PathBuf::from)
+        .or_else(|| DevContainer::find_workspace(Path::new(WORKSPACES)))
+    {
+        Some(path) => match DevContainer::read(&path) {
+            Ok(devcontainer) => devcontainer,
+            Err(err) => {
+                error!("{err}");
+                exit(1);
+            }
+        },
+        None => DevContainer::default(),
+    };
+    let agent = Agent {
+        session_sender: session_sender.clone(),
+        manual: manual.clone(),
+        replies: replies.clone(),
+        port_register: port_register.clone(),
+        devcontainer: devcontainer.clone(),
+    };
+    info!("Control socket at {}", control_socket.display());
+    thread::spawn(move || serve_requests(control_listener, move |request| agent.handle(request)));
+    let reverse = match reverse_ports(&config.container.reverse) {
+        Ok(reverse) => reverse,
+        Err(err) => {
+            error!("{err}");
+            exit(1);
+        }
+    };
+    for &(port, host_port) in &reverse {
+        let listener = match TcpListener::bind(("localhost", port)) {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("ERROR: Unable to listen on Port {port} for Port {host_port} of the Host\n{err}");
+                exit(1);
+            }
+        };
+        info!("Forwarding Port {port} to Port {host_port} of the Host");
+        let sender = session_sender.clone();
+        let streams = streams.clone();
+        thread::spawn(move || {
+            reverse_listener(listener, port, host_port, sender, streams, &REVERSE)
+        });
+    }
+    let port_manager_devcontainer = devcontainer.clone();
+    let port_manager_manual = manual.clone();
+    let port_manager_reverse = reverse.iter().map(|(port, _)| *port).collect();
+    thread::spawn(move || {
+        port_manager(
+            port_manager_sender,
+            port_manager_register,
+            port_manager_manual,
+            port_manager_reverse,
+            detector,
+            rules,
+            port_manager_devcontainer,
+        )
+    });
+    loop {
+        let stream = match stdio {
+            true => attach_to_host(&config, &hello),
+            false => connect_to_host(&config, &hello),
+        };
+        let (sender, receiver) = channel();
+        announce_ports(
+            &session_sender,
+            sender.clone(),
+            &port_register,
+            &manual,
+            &replies,
+            &devcontainer,
+        );
+        let read_stream = stream.try_clone().expect("Unable to clone stream");
+        let write_stream = stream.try_clone().expect("Unable to clone stream");
+        let register = port_register.clone();
+        let session_replies = replies.clone();
+        let session_streams = streams.clone();
+        thread::spawn(move || {
+            client_read_stream(
+                read_stream,
+                sender.clone(),
+                register,
+                session_replies,
+                session_streams,
+            );
+            // Wakes up the writer, which stops after the Close or at the broken socket.
+            let _ = sender.send(create_message(0, Function::Close, Vec::new()));
+        });
+        client_write_stream(write_stream, receiver);
+        let _ = stream.shutdown(Shutdown::Both);
+        if CLOSING.load(Ordering::SeqCst) || stdio {
+            let _ = fs::remove_file(&control_socket);
+            break;
+        }
+        info!("Lost the Connection to the Host");
+    }
+}
+
+#[cfg(test)]
+mod test_agent {
+    use super::*;
+
+    #[test]
+    fn list_while_forwarding() {
+        let (sender, requests) = channel::<Message>();
+        let agent = Agent {
+            session_sender: Arc::new(Mutex::new(sender)),
+            manual: ManualPorts::default(),
+            replies: Replies::default(),
+            port_register: Arc::default(),
+            devcontainer: DevContainer::default(),
+        };
+        // The Host forwards the even ports and refuses the odd ones.
+        let replies = agent.replies.clone();
+        thread::spawn(move || {
+            for request in requests {
+                let port = request.header.port;
+                let reply = match port % 2 {
+                    0 => PortReply::Forwarded(([127, 0, 0, 1], port).into()),
+                    _ => PortReply::Refused("taken".to_string()),
+                };
+                replies
+                    .write()
+                    .unwrap()
+                    .insert((port, Protocol::TCP), reply);
+            }
+        });
+        let (done, finished) = channel();
+        for client in 0..4 {
+            let (agent, done) = (agent.clone(), done.clone());
+            thread::spawn(move || {
+                for port in 0..20 {
+                    let request = match client % 2 {
+                        0 => AgentRequest::Forward {
+                            port: 3000 + 20 * client + port,
+                            protocol: Protocol::TCP,
+                            host_port: None,
+                        },
+                        _ => AgentRequest::List,
+                    };
+                    agent.handle(request);
+                }
+                done.send(()).unwrap();
+            });
+        }
+        for _ in 0..4 {
+            finished
+                .recv_timeout(Duration::from_secs(10))
+                .expect("the agent is deadlocked");
+        }
+        assert_eq!(20, agent.ports().len());
+    }
 }
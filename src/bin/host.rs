@@ -1,28 +1,516 @@
-use auto_forward::Multiplexer;
+use auto_forward::{
+    accept_hello_with_token, bind_control, bind_unix, create_message, error, info, local_hostname,
+    reverse_ports, set_log_level, split_options, Config, Control, ControlClient, ControlRequest,
+    ControlResponse, DevContainer, Function, Hello, HostPorts, Multiplexer, Pipe, PortPolicy,
+    PortSpec, SecureStream, SessionInfo, Transport, Tunnel,
+};
 use std::env;
+use std::fs;
+use std::io;
 use std::net::TcpListener;
-use std::process::exit;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{exit, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time a Container gets to encrypt the Session and to complete the HELLO.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Time before a spawned command, which exited, is started again.
+const RESPAWN_DELAY: Duration = Duration::from_secs(5);
+
+/// Time the Containers get to acknowledge the end of their sessions, before the Host exits anyway.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Set once the Host shuts down, so reconnecting Containers are turned away.
+static CLOSING: AtomicBool = AtomicBool::new(false);
+
+/// Subcommands, which talk to the running Host over its control socket.
+const COMMANDS: &str = "Commands of the running Host:
+  host status                                   Version, Sessions and whether auto forwarding is paused
+  host ls                                       Forwarded ports of all Containers
+  host forward <container> <port>[:<host port>] Forwards a port, e.g. devbox 8080:9090 or 1 53/udp
+  host unforward <container> <port>             Stops forwarding a port
+  host logs [-f]                                Recent output of the Host, -f follows it
+  host token                                    Token the Containers authenticate with, as AUTO_FORWARD_TOKEN
+A <container> is the id of its Session, its hostname or its devcontainer.";
+
+/// Shared by the listeners of the Host, the TCP port and the Unix socket.
+#[derive(Clone)]
+struct Sessions {
+    control: Control,
+    host_ports: HostPorts,
+    token: Arc<String>,
+    encrypt: bool,
+    next_id: Arc<AtomicU32>,
+}
+
+/// Runs a Session for every Container connecting, until the Host shuts down.
+fn accept_sessions<T, I>(incoming: I, sessions: Sessions)
+where
+    T: Into<Tunnel> + Send + 'static,
+    I: Iterator<Item = io::Result<T>>,
+{
+    for stream in incoming {
+        match stream {
+            Ok(_) if CLOSING.load(Ordering::SeqCst) => {}
+            Ok(stream) => {
+                let id = sessions.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+                let sessions = sessions.clone();
+                thread::spawn(move || {
+                    let stream = stream.into();
+                    info!("Session {id}: Connection from {}", stream.peer());
+                    match handshake(stream, &sessions, false) {
+                        Ok((tunnel, peer)) => run_session(id, tunnel, peer, sessions),
+                        Err(err) => refuse(id, &err),
+                    }
+                });
+            }
+            Err(err) => error!("Unable to accept connection!\n{err}"),
+        }
+    }
+}
+
+/// Runs `command` with `sh -c` and a Session over its stdin and stdout, again whenever it exits.
+fn spawn_sessions(command: String, sessions: Sessions) {
+    while !CLOSING.load(Ordering::SeqCst) {
+        let id = sessions.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        if let Err(err) = spawn_session(id, &command, &sessions, HANDSHAKE_TIMEOUT) {
+            error!("{err}");
+        }
+        if CLOSING.load(Ordering::SeqCst) {
+            break;
+        }
+        info!(
+            "Spawning {command} again in {} seconds",
+            RESPAWN_DELAY.as_secs()
+        );
+        thread::sleep(RESPAWN_DELAY);
+    }
+}
+
+/// Runs `command` once, with a Session over its stdin and stdout until either of them ends.
+///
+/// Pipes have no read timeouts, so the HELLO runs on its own thread, and the command is killed
+/// if it does not complete it within `timeout`, e.g. `docker exec` of a stopped Container.
+fn spawn_session(
+    id: u32,
+    command: &str,
+    sessions: &Sessions,
+    timeout: Duration,
+) -> Result<(), String> {
+    // Its own process group, so the commands started by the shell are killed along with it.
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .map_err(|err| format!("ERROR: Unable to spawn {command}\n{err}"))?;
+    info!("Session {id}: Spawned {command}");
+    let stdin = child.stdin.take().expect("Piped stdin");
+    let stdout = child.stdout.take().expect("Piped stdout");
+    let (sender, receiver) = channel();
+    let handshake_sessions = sessions.clone();
+    thread::spawn(move || {
+        let stream = Pipe::new(stdout, stdin).into();
+        let _ = sender.send(handshake(stream, &handshake_sessions, true));
+    });
+    match receiver.recv_timeout(timeout) {
+        Ok(Ok((tunnel, peer))) => run_session(id, tunnel, peer, sessions.clone()),
+        Ok(Err(err)) => refuse(id, &err),
+        Err(_) => refuse(
+            id,
+            &format!(
+                "ERROR: {command} did not complete the HELLO within {} seconds",
+                timeout.as_secs()
+            ),
+        ),
+    }
+    // SAFETY: kill only takes integers, the group is gone at the latest once the child is reaped.
+    unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) };
+    let _ = child.wait();
+    Ok(())
+}
+
+/// Encrypts the Session and completes the HELLO, `spawned` Sessions were started by the Host
+/// itself and are trusted, so they are neither authenticated nor encrypted.
+fn handshake(
+    stream: Tunnel,
+    sessions: &Sessions,
+    spawned: bool,
+) -> Result<(Tunnel, Hello), String> {
+    let token = sessions.token.as_str();
+    // Only Containers knowing the token get a Multiplexer, which opens listeners on their behalf.
+    let hello = Hello::new(local_hostname(), None);
+    stream
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .map_err(|err| format!("ERROR: Unable to set the timeout of the HELLO\n{err}"))?;
+    let mut tunnel = match sessions.encrypt && !spawned {
+        true => SecureStream::accept(stream, token).map(Tunnel::from)?,
+        false => stream,
+    };
+    let token = (!spawned).then_some(token);
+    let peer = accept_hello_with_token(&mut tunnel, &hello, token)?;
+    let _ = tunnel.set_read_timeout(None);
+    Ok((tunnel, peer))
+}
+
+fn refuse(id: u32, err: &str) {
+    error!("{err}");
+    info!("Session {id}: Refused");
+}
+
+/// Runs the Session of a Container, which completed the HELLO as `peer`.
+fn run_session(id: u32, tunnel: Tunnel, peer: Hello, sessions: Sessions) {
+    let Sessions {
+        control,
+        host_ports,
+        ..
+    } = sessions;
+    let multi = Multiplexer::with_host_ports(tunnel, host_ports).with_peer(peer);
+    control.add_session(id, multi.handle());
+    // A failing session must never take down the Host or the sessions of other Containers.
+    if thread::spawn(move || multi.run()).join().is_err() {
+        error!("ERROR: Session {id} terminated unexpectedly");
+    }
+    control.remove_session(id);
+    info!("Session {id}: Closed");
+}
+
+fn close_sessions(control: &Control, sockets: &[PathBuf]) {
+    info!("Closing all Sessions");
+    CLOSING.store(true, Ordering::SeqCst);
+    for session in control.sessions() {
+        let _ = session
+            .sender()
+            .send(create_message(0, Function::Close, Vec::new()));
+    }
+    let start = Instant::now();
+    while !control.sessions().is_empty() && start.elapsed() < CLOSE_TIMEOUT {
+        thread::sleep(Duration::from_millis(100));
+    }
+    for socket in sockets {
+        let _ = fs::remove_file(socket);
+    }
+    exit(0);
+}
 
 fn main() {
-    let port = env::args()
-        .nth(1)
-        .unwrap_or("28258".to_string())
-        .parse::<u16>()
-        .unwrap_or(28258);
-    let socket =
-        TcpListener::bind(format!("127.0.0.1:{port}")).expect("ERROR: Unable to create Socket");
-    println!("Listening on Port {port} for connections");
-    let stream = match socket.accept() {
-        Ok((stream, addr)) => {
-            println!("Connection from {addr}");
-            stream
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    match args.first().map(String::as_str) {
+        Some("token") => print_token(&args[1..]),
+        Some(command @ ("status" | "ls" | "forward" | "unforward" | "logs")) => {
+            run_command(command, &args[1..])
+        }
+        _ => run_host(&args),
+    }
+}
+
+/// Runs the Host, which accepts the Containers until it is stopped.
+fn run_host(args: &[String]) {
+    let config = match Config::load(args) {
+        Ok(config) => config,
+        Err(err) => {
+            error!("{err}");
+            exit(1);
+        }
+    };
+    set_log_level(config.log);
+    // Shared by all sessions, so a reconnecting Container gets its previous host ports again.
+    let policy = match PortPolicy::parse(&config.host.port_policy, &config.host.port_map) {
+        Ok(policy) => policy,
+        Err(err) => {
+            error!("{err}");
+            exit(1);
+        }
+    };
+    let reverse = match reverse_ports(&config.host.reverse) {
+        Ok(ports) => ports.into_iter().map(|(_, host_port)| host_port).collect(),
+        Err(err) => {
+            error!("{err}");
+            exit(1);
         }
+    };
+    let mut host_ports = HostPorts::with_bind(&config.host.bind)
+        .with_policy(policy)
+        .with_reverse(reverse);
+    if let Some(workspace) = &config.host.workspace {
+        match DevContainer::read(Path::new(workspace)) {
+            Ok(devcontainer) => host_ports = host_ports.with_devcontainer(devcontainer),
+            Err(err) => {
+                error!("{err}");
+                exit(1);
+            }
+        }
+    }
+    let token = match config.host.token() {
+        Ok(token) => Arc::new(token),
+        Err(err) => {
+            error!("{err}");
+            exit(1);
+        }
+    };
+    let control_listener = config
+        .host
+        .control_socket()
+        .and_then(|control_socket| Ok((bind_control(&control_socket, 0o600)?, control_socket)));
+    let (control_listener, control_socket) = match control_listener {
+        Ok(listener) => listener,
         Err(err) => {
-            eprintln!("Unable to accept connection!\n{err}");
+            error!("{err}");
             exit(1);
         }
     };
-    let multi = thread::spawn(|| Multiplexer::new(stream).run());
-    multi.join().unwrap();
+    let port = config.port;
+    let socket = TcpListener::bind((config.host.address.as_str(), port))
+        .expect("ERROR: Unable to create Socket");
+    info!("Listening on Port {port} for connections");
+    let mut sockets = vec![control_socket.clone()];
+    let unix_socket = match &config.host.socket {
+        // Containers may run as any user, the token decides who gets a Session.
+        Some(path) => match bind_unix(Path::new(path), 0o666, "socket") {
+            Ok(listener) => {
+                info!("Listening on {path} for connections");
+                sockets.push(PathBuf::from(path));
+                Some(listener)
+            }
+            Err(err) => {
+                error!("{err}");
+                exit(1);
+            }
+        },
+        None => None,
+    };
+    let control = Control::new();
+    info!("Control socket at {}", control_socket.display());
+    let serving = control.clone();
+    thread::spawn(move || serving.serve(control_listener));
+    let handler_control = control.clone();
+    ctrlc::set_handler(move || close_sessions(&handler_control, &sockets))
+        .expect("ERROR: Unable to register the shutdown handler");
+    let sessions = Sessions {
+        control,
+        host_ports,
+        token,
+        encrypt: config.encrypt,
+        next_id: Arc::new(AtomicU32::new(0)),
+    };
+    for command in config.host.spawn.clone() {
+        let sessions = sessions.clone();
+        thread::spawn(move || spawn_sessions(command, sessions));
+    }
+    if let Some(listener) = unix_socket {
+        let sessions = sessions.clone();
+        thread::spawn(move || accept_sessions(listener.incoming(), sessions));
+    }
+    accept_sessions(socket.incoming(), sessions);
+}
+
+/// Prints the token of the Host, which is generated if there is none yet.
+fn print_token(args: &[String]) {
+    match Config::load(args).and_then(|config| config.host.token()) {
+        Ok(token) => println!("{token}"),
+        Err(err) => {
+            error!("{err}");
+            exit(1);
+        }
+    }
+}
+
+/// Runs a subcommand against the running Host, found by the control socket of the configuration.
+fn run_command(command: &str, args: &[String]) {
+    let (arguments, options) = split_options(args);
+    let arguments = arguments.iter().map(String::as_str).collect::<Vec<&str>>();
+    let result = Config::load(&options).and_then(|config| {
+        let mut client = ControlClient::connect(&config.host.control_socket()?)?;
+        match (command, arguments.as_slice()) {
+            ("status", []) => status(&mut client),
+            ("ls", []) => list(&mut client),
+            ("forward", [container, port]) => forward(&mut client, container, port),
+            ("unforward", [container, port]) => unforward(&mut client, container, port),
+            ("logs", []) => logs(&mut client, false),
+            ("logs", ["-f"]) => logs(&mut client, true),
+            _ => Err(format!("ERROR: Invalid arguments of {command}\n{COMMANDS}")),
+        }
+    });
+    if let Err(err) = result {
+        error!("{err}");
+        exit(1);
+    }
+}
+
+fn sessions(response: ControlResponse) -> Vec<SessionInfo> {
+    response.sessions.unwrap_or_default()
+}
+
+fn status(client: &mut ControlClient) -> Result<(), String> {
+    let response = client.request(&ControlRequest::Status)?;
+    let paused = match response.paused {
+        Some(true) => "paused",
+        _ => "active",
+    };
+    let version = response.version.clone().unwrap_or_default();
+    println!("autoForward {version} is running, auto forwarding is {paused}");
+    for session in sessions(response) {
+        let paused = if session.paused { ", paused" } else { "" };
+        println!(
+            "Session {}: {}, {} forwarded ports{paused}",
+            session.id,
+            session.container(),
+            session.ports.len()
+        );
+    }
+    Ok(())
+}
+
+fn list(client: &mut ControlClient) -> Result<(), String> {
+    let mut rows = vec![[
+        "CONTAINER",
+        "PORT",
+        "HOST PORT",
+        "PROTOCOL",
+        "APP",
+        "CONNECTIONS",
+        "BYTES",
+    ]
+    .map(String::from)];
+    for session in sessions(client.request(&ControlRequest::List)?) {
+        for port in &session.ports {
+            let app = match &port.label {
+                Some(label) => format!("{} ({label})", port.app),
+                None => port.app.clone(),
+            };
+            rows.push([
+                session.container(),
+                port.port.to_string(),
+                port.host_port.to_string(),
+                format!("{:?}", port.protocol),
+                app,
+                port.connections.to_string(),
+                port.bytes.to_string(),
+            ]);
+        }
+    }
+    let mut widths = [0; 7];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<String>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+    Ok(())
+}
+
+/// The session of `container`, which has to be unique.
+fn find_session(client: &mut ControlClient, container: &str) -> Result<SessionInfo, String> {
+    let mut matching = sessions(client.request(&ControlRequest::List)?)
+        .into_iter()
+        .filter(|session| session.matches(container))
+        .collect::<Vec<SessionInfo>>();
+    match matching.len() {
+        0 => Err(format!("ERROR: No Container {container} is connected")),
+        1 => Ok(matching.remove(0)),
+        _ => Err(format!(
+            "ERROR: {container} matches the Sessions {}, use the id of the Session",
+            matching
+                .iter()
+                .map(|session| session.id.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        )),
+    }
+}
+
+fn forward(client: &mut ControlClient, container: &str, port: &str) -> Result<(), String> {
+    let spec = PortSpec::parse(port)?;
+    let session = find_session(client, container)?;
+    let response = client.request(&ControlRequest::Forward {
+        session: session.id,
+        port: spec.port,
+        protocol: spec.protocol.clone(),
+        label: None,
+        host_port: spec.host_port,
+    })?;
+    if let Some(address) = response.address {
+        println!(
+            "Forwarding {:?} Port {} of {} to {address}",
+            spec.protocol,
+            spec.port,
+            session.container()
+        );
+    }
+    Ok(())
+}
+
+fn unforward(client: &mut ControlClient, container: &str, port: &str) -> Result<(), String> {
+    let spec = PortSpec::parse(port)?;
+    let session = find_session(client, container)?;
+    client.request(&ControlRequest::Unforward {
+        session: session.id,
+        port: spec.port,
+        protocol: spec.protocol,
+    })?;
+    Ok(())
+}
+
+fn logs(client: &mut ControlClient, follow: bool) -> Result<(), String> {
+    let response = client.request(&ControlRequest::Logs { follow })?;
+    for line in response.log.unwrap_or_default() {
+        println!("{line}");
+    }
+    if !follow {
+        return Ok(());
+    }
+    loop {
+        match client.receive::<String>()? {
+            Some(line) => println!("{line}"),
+            None => return Err("ERROR: The Host stopped".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_host {
+    use super::*;
+
+    /// Processes running `sleep 60`, started by the shell of a command.
+    fn sleeping() -> usize {
+        fs::read_dir("/proc")
+            .unwrap()
+            .flatten()
+            .filter_map(|process| fs::read(process.path().join("cmdline")).ok())
+            .filter(|cmdline| cmdline == b"sleep\x0060\x00")
+            .count()
+    }
+
+    #[test]
+    fn kill_silent_command() {
+        let sessions = Sessions {
+            control: Control::new(),
+            host_ports: HostPorts::new(),
+            token: Arc::new("secret".to_string()),
+            encrypt: false,
+            next_id: Arc::new(AtomicU32::new(0)),
+        };
+        let start = Instant::now();
+        // Not the last command, so the shell forks it instead of replacing itself.
+        spawn_session(1, "sleep 60; true", &sessions, Duration::from_millis(200)).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(0, sleeping());
+        assert!(sessions.control.sessions().is_empty());
+    }
 }
@@ -0,0 +1,558 @@
+#[cfg(unix)]
+use crate::check_private;
+use crate::{load_token, LogLevel};
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Port of the Host, which the Containers connect to.
+pub const DEFAULT_PORT: u16 = 28258;
+
+/// Settings of the Host and the Container, read from a TOML file like:
+///
+/// ```toml
+/// port = 28258
+/// log = "info"
+/// encrypt = false
+///
+/// [host]
+/// address = "127.0.0.1"
+/// bind = "localhost"
+/// port_policy = "fixed"
+/// port_map = ["3000:8080", "5432:0"]
+/// workspace = "/home/user/app"
+/// control = "/run/user/1000/auto_forward.sock"
+/// reverse = ["5432"]
+/// socket = "/home/user/app/.auto_forward.sock"
+/// spawn = ["docker exec -i devbox container --stdio"]
+///
+/// [container]
+/// host = "host.docker.internal"
+/// devcontainer = "/workspaces/app"
+/// control = "/tmp/auto_forward.sock"
+/// detector = "netlink"
+/// poll_interval = 5
+/// ports = ["8080", "53/udp"]
+/// rules = ["allow 9229 app=node", "deny 9000-9999"]
+/// reverse = ["5432", "2222:22"]
+/// socket = "/workspaces/app/.auto_forward.sock"
+/// ```
+///
+/// Every setting can be overwritten by an environment variable, which in turn can be
+/// overwritten by a command line argument.
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub port: u16,
+    pub log: LogLevel,
+    /// Encrypts the Sessions with the token of the Host, see `SecureStream`.
+    pub encrypt: bool,
+    pub host: HostConfig,
+    pub container: ContainerConfig,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HostConfig {
+    /// Address the Host accepts Containers on.
+    pub address: String,
+    /// Address the forwarded ports are bound to on the Host.
+    pub bind: String,
+    /// How host ports are picked, see `PortPolicy::parse`.
+    pub port_policy: String,
+    /// Host ports of the `fixed` policy, like `3000:8080`.
+    pub port_map: Vec<String>,
+    /// Workspace folder or `devcontainer.json`, whose forwarding settings apply to every Container.
+    pub workspace: Option<String>,
+    /// Path of the control socket, see `HostConfig::control_socket`.
+    pub control: Option<String>,
+    /// Ports of the Host the Containers may connect to, like `5432` or `15432:5432`.
+    pub reverse: Vec<String>,
+    /// Token the Containers have to know, see `HostConfig::token`.
+    pub token: Option<String>,
+    /// Unix socket the Host accepts Containers on, next to the TCP port.
+    pub socket: Option<String>,
+    /// Commands running `container --stdio`, the Host has a Session over the stdin and stdout of
+    /// each of them.
+    pub spawn: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContainerConfig {
+    /// Address of the Host, as seen from inside the Container.
+    pub host: String,
+    /// Workspace folder or `devcontainer.json` with the forwarding settings, the only workspace
+    /// in `/workspaces` is used if it is not set.
+    pub devcontainer: Option<String>,
+    /// Path of the control socket of the agent, see `ContainerConfig::control_socket`.
+    pub control: Option<String>,
+    /// Permissions of the control socket of the agent, e.g. `0o666` for subcommands of other users.
+    pub control_mode: u32,
+    /// Name of the `PortDetector`, the best one working is used if it is not set.
+    pub detector: Option<String>,
+    /// Seconds between two scans of the lsof and /proc detectors.
+    pub poll_interval: u64,
+    /// Ports of the `static` detector, e.g. `8080` or `53/udp`.
+    pub ports: Vec<String>,
+    /// Forwarding rules, see `Rule::parse`.
+    pub rules: Vec<String>,
+    /// Ports of the Host reachable in the Container, like `5432` or `15432:5432` for Port 5432 of
+    /// the Host on Port 15432 of the Container.
+    pub reverse: Vec<String>,
+    /// Token of the Host, printed by `host token`.
+    pub token: Option<String>,
+    /// Unix socket of the Host, e.g. in a bind mount, used instead of `host` and the port.
+    pub socket: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            port: DEFAULT_PORT,
+            log: LogLevel::default(),
+            encrypt: false,
+            host: HostConfig::default(),
+            container: ContainerConfig::default(),
+        }
+    }
+}
+
+impl Default for HostConfig {
+    fn default() -> HostConfig {
+        HostConfig {
+            address: "127.0.0.1".to_string(),
+            bind: "localhost".to_string(),
+            port_policy: "same-or-next".to_string(),
+            port_map: Vec::new(),
+            workspace: None,
+            control: None,
+            reverse: Vec::new(),
+            token: None,
+            socket: None,
+            spawn: Vec::new(),
+        }
+    }
+}
+
+impl Default for ContainerConfig {
+    fn default() -> ContainerConfig {
+        ContainerConfig {
+            host: "host.docker.internal".to_string(),
+            devcontainer: None,
+            control: None,
+            control_mode: 0o600,
+            detector: None,
+            poll_interval: 5,
+            ports: Vec::new(),
+            rules: Vec::new(),
+            reverse: Vec::new(),
+            token: None,
+            socket: None,
+        }
+    }
+}
+
+impl HostConfig {
+    /// The configured control socket, otherwise `auto_forward.sock` in `$XDG_RUNTIME_DIR` or in a
+    /// directory of this user in the temporary directory.
+    #[cfg(unix)]
+    pub fn control_socket(&self) -> Result<PathBuf, String> {
+        match &self.control {
+            Some(control) => Ok(PathBuf::from(control)),
+            None => user_socket("auto_forward"),
+        }
+    }
+
+    /// The configured token, otherwise the one stored next to the configuration, which is
+    /// generated on first use.
+    pub fn token(&self) -> Result<String, String> {
+        if let Some(token) = &self.token {
+            return Ok(token.clone());
+        }
+        let path = match config_dir() {
+            Some(config) => config.join("auto_forward/token"),
+            None => {
+                let user = env::var("USER").unwrap_or_default();
+                env::temp_dir().join(format!("auto_forward-{user}.token"))
+            }
+        };
+        load_token(&path)
+    }
+}
+
+impl ContainerConfig {
+    /// The configured control socket, otherwise `auto_forward-agent.sock` in `$XDG_RUNTIME_DIR` or
+    /// in a directory of this user in the temporary directory.
+    ///
+    /// Subcommands of other users than the agent need the same `control` and a wider
+    /// `control_mode`.
+    #[cfg(unix)]
+    pub fn control_socket(&self) -> Result<PathBuf, String> {
+        match &self.control {
+            Some(control) => Ok(PathBuf::from(control)),
+            None => user_socket("auto_forward-agent"),
+        }
+    }
+
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval.max(1))
+    }
+}
+
+/// Options of both Programs, all of them take a value.
+const USAGE: &str = "Options:
+  <port>                    Port of the Host, same as --port
+  --config <file>           Configuration file
+  --port <port>             Port of the Host
+  --log <error|info>        Log level
+  --encrypt <true|false>    Encrypts the Sessions, on the Host and in the Container
+  --address <address>       Address the Host accepts Containers on
+  --bind <address>          Address the forwarded ports are bound to on the Host
+  --port-policy <policy>    Host port of a taken port: same-or-fail, same-or-next, same-or-ephemeral or fixed
+  --port-map <list>         Host ports of the fixed policy, e.g. 3000:8080,5432:0
+  --workspace <path>        Workspace folder or devcontainer.json, read by the Host
+  --control <path>          Control socket of the Host or of the agent in the Container
+  --control-mode <mode>     Permissions of the control socket of the agent, 0o600 by default
+  --host <address>          Address of the Host, as seen from the Container
+  --devcontainer <path>     Workspace folder or devcontainer.json, read by the Container
+  --detector <name>         Port detector of the Container: netlink, proc, lsof or static
+  --poll-interval <seconds> Seconds between two scans of the lsof and /proc detectors
+  --ports <list>            Ports of the static detector, e.g. 8080,53/udp
+  --rules <list>            Forwarding rules, e.g. \"allow 9229, deny 9000-9999\"
+  --reverse <list>          Ports of the Host reachable in the Container, e.g. 5432,2222:22
+  --token <token>           Token the Containers authenticate with, see host token
+  --socket <path>           Unix socket between Host and Container, e.g. in a bind mount
+  --spawn <list>            Commands the Host runs container --stdio with, e.g. \"docker exec -i devbox container --stdio\"";
+
+impl Config {
+    /// Loads the configuration file, applies the environment and then the arguments.
+    ///
+    /// `args` are the command line arguments without the name of the program.
+    pub fn load(args: &[String]) -> Result<Config, String> {
+        let explicit = match args.iter().position(|arg| arg == "--config") {
+            Some(index) => Some(PathBuf::from(
+                args.get(index + 1)
+                    .ok_or(format!("ERROR: --config requires a value\n{USAGE}"))?,
+            )),
+            None => env::var("AUTO_FORWARD_CONFIG").ok().map(PathBuf::from),
+        };
+        let mut config = match explicit {
+            // A file given explicitly has to exist.
+            Some(path) => Config::read(&path)?,
+            None => match config_paths().into_iter().find(|path| path.exists()) {
+                Some(path) => Config::read(&path)?,
+                None => Config::default(),
+            },
+        };
+        config.apply_env(|key| env::var(key).ok())?;
+        config.apply_args(args)?;
+        Ok(config)
+    }
+
+    pub fn read(path: &Path) -> Result<Config, String> {
+        let content = fs::read_to_string(path).map_err(|err| {
+            format!(
+                "ERROR: Unable to read the configuration {}\n{err}",
+                path.display()
+            )
+        })?;
+        Config::parse(&content)
+            .map_err(|err| format!("ERROR: Invalid configuration {}\n{err}", path.display()))
+    }
+
+    pub fn parse(content: &str) -> Result<Config, String> {
+        toml::from_str(content).map_err(|err| err.to_string())
+    }
+
+    fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<(), String> {
+        for (key, option) in ENV_OPTIONS {
+            if let Some(value) = var(key) {
+                self.set(option, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_args(&mut self, args: &[String]) -> Result<(), String> {
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some("config") => {
+                    args.next();
+                }
+                Some(option) => {
+                    let value = args
+                        .next()
+                        .ok_or(format!("ERROR: --{option} requires a value\n{USAGE}"))?;
+                    self.set(option, value)?;
+                }
+                // The port used to be the only argument.
+                None => self.set("port", arg)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, option: &str, value: &str) -> Result<(), String> {
+        let list = |value: &str| {
+            value
+                .split(',')
+                .map(|entry| entry.trim().to_string())
+                .filter(|entry| !entry.is_empty())
+                .collect::<Vec<String>>()
+        };
+        match option {
+            "port" => {
+                self.port = value
+                    .parse::<u16>()
+                    .map_err(|_| format!("ERROR: {value} is not a valid Port"))?
+            }
+            "log" => self.log = LogLevel::decode(value)?,
+            "encrypt" => {
+                self.encrypt = value
+                    .parse::<bool>()
+                    .map_err(|_| format!("ERROR: {value} is neither true nor false"))?
+            }
+            "address" => self.host.address = value.to_string(),
+            "bind" => self.host.bind = value.to_string(),
+            "port-policy" => self.host.port_policy = value.to_string(),
+            "port-map" => self.host.port_map = list(value),
+            "workspace" => self.host.workspace = Some(value.to_string()),
+            "spawn" => self.host.spawn = list(value),
+            // Each Program only uses its own control socket.
+            "control" => {
+                self.host.control = Some(value.to_string());
+                self.container.control = Some(value.to_string());
+            }
+            "control-mode" => {
+                self.container.control_mode = u32::from_str_radix(value.trim_start_matches("0o"), 8)
+                    .ok()
+                    .filter(|mode| *mode <= 0o777)
+                    .ok_or(format!("ERROR: {value} is no file mode like 0o666"))?
+            }
+            "host" => self.container.host = value.to_string(),
+            "devcontainer" => self.container.devcontainer = Some(value.to_string()),
+            "detector" => self.container.detector = Some(value.to_string()),
+            "poll-interval" => {
+                self.container.poll_interval = value
+                    .parse::<u64>()
+                    .map_err(|_| format!("ERROR: {value} is not a valid number of seconds"))?
+            }
+            "ports" => self.container.ports = list(value),
+            "rules" => self.container.rules = list(value),
+            // The Host allows the ports the Container listens for.
+            "reverse" => {
+                self.host.reverse = list(value);
+                self.container.reverse = list(value);
+            }
+            "token" => {
+                self.host.token = Some(value.to_string());
+                self.container.token = Some(value.to_string());
+            }
+            // The same path, as long as the directory is mounted at the same place.
+            "socket" => {
+                self.host.socket = Some(value.to_string());
+                self.container.socket = Some(value.to_string());
+            }
+            _ => return Err(format!("ERROR: Unknown option --{option}\n{USAGE}")),
+        }
+        Ok(())
+    }
+}
+
+/// Splits the arguments of a subcommand into its own arguments and the options with their values.
+pub fn split_options(args: &[String]) -> (Vec<String>, Vec<String>) {
+    let (mut arguments, mut options) = (Vec::new(), Vec::new());
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.starts_with("--") {
+            true => options.extend([Some(arg), args.next()].into_iter().flatten().cloned()),
+            false => arguments.push(arg.clone()),
+        }
+    }
+    (arguments, options)
+}
+
+/// Environment variables and the option they overwrite.
+const ENV_OPTIONS: [(&str, &str); 20] = [
+    ("AUTO_FORWARD_PORT", "port"),
+    ("AUTO_FORWARD_LOG", "log"),
+    ("AUTO_FORWARD_ENCRYPT", "encrypt"),
+    ("AUTO_FORWARD_ADDRESS", "address"),
+    ("AUTO_FORWARD_BIND", "bind"),
+    ("AUTO_FORWARD_PORT_POLICY", "port-policy"),
+    ("AUTO_FORWARD_PORT_MAP", "port-map"),
+    ("AUTO_FORWARD_WORKSPACE", "workspace"),
+    ("AUTO_FORWARD_CONTROL", "control"),
+    ("AUTO_FORWARD_CONTROL_MODE", "control-mode"),
+    ("AUTO_FORWARD_HOST", "host"),
+    ("AUTO_FORWARD_DEVCONTAINER", "devcontainer"),
+    ("AUTO_FORWARD_DETECTOR", "detector"),
+    ("AUTO_FORWARD_POLL_INTERVAL", "poll-interval"),
+    ("AUTO_FORWARD_PORTS", "ports"),
+    ("AUTO_FORWARD_RULES", "rules"),
+    ("AUTO_FORWARD_REVERSE", "reverse"),
+    ("AUTO_FORWARD_TOKEN", "token"),
+    ("AUTO_FORWARD_SOCKET", "socket"),
+    ("AUTO_FORWARD_SPAWN", "spawn"),
+];
+
+/// The socket `name` in `$XDG_RUNTIME_DIR`, otherwise in `auto_forward-<uid>` in the temporary
+/// directory.
+///
+/// `$USER` is often not set in a Container, e.g. in its entrypoint.
+#[cfg(unix)]
+fn user_socket(name: &str) -> Result<PathBuf, String> {
+    if let Ok(runtime) = env::var("XDG_RUNTIME_DIR") {
+        return Ok(PathBuf::from(runtime).join(format!("{name}.sock")));
+    }
+    // SAFETY: geteuid has no arguments and always succeeds.
+    let uid = unsafe { libc::geteuid() };
+    let directory = private_dir(&env::temp_dir().join(format!("auto_forward-{uid}")))?;
+    Ok(directory.join(format!("{name}.sock")))
+}
+
+/// Creates the directory `path`, which only this user may enter.
+///
+/// Everybody may create it first in a shared directory, so an existing one has to be private as
+/// well.
+#[cfg(unix)]
+fn private_dir(path: &Path) -> Result<PathBuf, String> {
+    use std::os::unix::fs::DirBuilderExt;
+    match fs::DirBuilder::new().mode(0o700).create(path) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(err) => {
+            return Err(format!(
+                "ERROR: Unable to create the directory {}\n{err}",
+                path.display()
+            ))
+        }
+    }
+    check_private(path, "directory")?;
+    Ok(path.to_path_buf())
+}
+
+/// `$XDG_CONFIG_HOME`, otherwise `~/.config`.
+fn config_dir() -> Option<PathBuf> {
+    match env::var("XDG_CONFIG_HOME") {
+        Ok(config) => Some(PathBuf::from(config)),
+        Err(_) => env::var("HOME")
+            .ok()
+            .map(|home| PathBuf::from(home).join(".config")),
+    }
+}
+
+/// Standard locations of the configuration file, the first existing one is used.
+fn config_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(config) = config_dir() {
+        paths.push(config.join("auto_forward/config.toml"));
+    }
+    paths.push(PathBuf::from("/etc/auto_forward/config.toml"));
+    paths
+}
+
+#[cfg(test)]
+mod test_config {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_file() {
+        let config = Config::parse(
+            r#"
+            port = 3000
+            log = "error"
+
+            [host]
+            bind = "0.0.0.0"
+
+            [container]
+            rules = ["deny 22", "allow tcp"]
+            control_mode = 0o666
+            "#,
+        )
+        .unwrap();
+        assert_eq!(3000, config.port);
+        assert_eq!(LogLevel::Error, config.log);
+        assert_eq!("0.0.0.0", config.host.bind);
+        assert_eq!("127.0.0.1", config.host.address);
+        assert_eq!("host.docker.internal", config.container.host);
+        assert_eq!(vec!["deny 22", "allow tcp"], config.container.rules);
+        assert_eq!(0o666, config.container.control_mode);
+        assert_eq!(Config::default(), Config::parse("").unwrap());
+        assert!(Config::parse("colour = true").is_err());
+        assert!(Config::parse("log = \"loud\"").is_err());
+    }
+
+    #[test]
+    fn overrides() {
+        let mut config = Config::parse("port = 3000\n[container]\nhost = \"file\"").unwrap();
+        config
+            .apply_env(|key| match key {
+                "AUTO_FORWARD_HOST" => Some("env".to_string()),
+                "AUTO_FORWARD_PORTS" => Some("8080, 53/udp".to_string()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!("env", config.container.host);
+        assert_eq!(vec!["8080", "53/udp"], config.container.ports);
+
+        config
+            .apply_args(&args(&["4000", "--host", "cli", "--config", "unused"]))
+            .unwrap();
+        assert_eq!(4000, config.port);
+        assert_eq!("cli", config.container.host);
+        assert!(config.apply_args(&args(&["--colour", "red"])).is_err());
+        assert!(config.apply_args(&args(&["--port"])).is_err());
+        assert!(config.apply_args(&args(&["--port", "70000"])).is_err());
+        config
+            .apply_args(&args(&["--control-mode", "666"]))
+            .unwrap();
+        assert_eq!(0o666, config.container.control_mode);
+        assert!(config
+            .apply_args(&args(&["--control-mode", "0o1777"]))
+            .is_err());
+        assert!(config.apply_args(&args(&["--control-mode", "rw"])).is_err());
+        config.apply_args(&args(&["--encrypt", "true"])).unwrap();
+        assert!(config.encrypt);
+        assert!(config.apply_args(&args(&["--encrypt", "yes"])).is_err());
+    }
+
+    #[test]
+    fn split_subcommand_options() {
+        let (arguments, options) = split_options(&args(&[
+            "devbox",
+            "--control",
+            "/tmp/af.sock",
+            "8080",
+            "-f",
+        ]));
+        assert_eq!(args(&["devbox", "8080", "-f"]), arguments);
+        assert_eq!(args(&["--control", "/tmp/af.sock"]), options);
+    }
+
+    #[test]
+    fn explicit_file_has_to_exist() {
+        assert!(Config::load(&args(&["--config", "/nonexistent/config.toml"])).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn private_directory() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = env::temp_dir().join(format!("auto_forward-dir-{}", std::process::id()));
+        assert_eq!(path, private_dir(&path).unwrap());
+        assert_eq!(path, private_dir(&path).unwrap());
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(private_dir(&path).is_err());
+        fs::remove_dir(&path).unwrap();
+    }
+}
@@ -0,0 +1,834 @@
+use crate::{
+    follow_log, recent_log, ForwardedPort, PortChange, PortRequest, Protocol, SessionHandle,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A request to the control socket of the Host, one JSON object per line like
+/// `{"command": "forward", "session": 1, "port": 8080}`.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ControlRequest {
+    /// The version of the Host, whether auto forwarding is paused and the sessions.
+    Status,
+    /// The sessions and their forwarded ports.
+    List,
+    /// Forwards a port of a Container, whether it is detected or not.
+    Forward {
+        session: u32,
+        port: u16,
+        #[serde(default)]
+        protocol: Protocol,
+        #[serde(default)]
+        label: Option<String>,
+        /// The host port, which has to be free, instead of the one picked by the port policy.
+        #[serde(default)]
+        host_port: Option<u16>,
+    },
+    Unforward {
+        session: u32,
+        port: u16,
+        #[serde(default)]
+        protocol: Protocol,
+    },
+    /// Refuses the ports requested by one Container, or by all Containers including future ones.
+    Pause {
+        #[serde(default)]
+        session: Option<u32>,
+    },
+    Resume {
+        #[serde(default)]
+        session: Option<u32>,
+    },
+    /// Turns the connection into a stream of `ControlEvent`s.
+    Subscribe,
+    /// The last lines printed by the Host, `follow` turns the connection into a stream of the
+    /// lines printed from then on.
+    Logs {
+        #[serde(default)]
+        follow: bool,
+    },
+}
+
+/// A request to the control socket of the agent in a Container, like
+/// `{"command": "forward", "port": 8080}`.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AgentRequest {
+    /// The ports requested from the Host and its answers.
+    List,
+    /// Requests a port from the Host, whether it is detected or not, until it is unforwarded.
+    Forward {
+        port: u16,
+        #[serde(default)]
+        protocol: Protocol,
+        #[serde(default)]
+        host_port: Option<u16>,
+    },
+    Unforward {
+        port: u16,
+        #[serde(default)]
+        protocol: Protocol,
+    },
+}
+
+/// A port the agent requested from the Host.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct RequestedPort {
+    pub port: u16,
+    pub protocol: Protocol,
+    pub app: String,
+    /// Forwarded with `AgentRequest::Forward`, instead of being detected.
+    pub manual: bool,
+    /// Address of the listener on the Host, once the Host answered.
+    pub address: Option<SocketAddr>,
+    /// Why the Host refused the port.
+    pub refused: Option<String>,
+}
+
+/// The answer to a `ControlRequest` or `AgentRequest`, only the fields of the request are set.
+#[derive(Debug, PartialEq, Clone, Default, Serialize, Deserialize)]
+pub struct ControlResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sessions: Option<Vec<SessionInfo>>,
+    /// Address of the listener on the Host of a forwarded port.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<SocketAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// Auto forwarding is paused for all sessions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paused: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ports: Option<Vec<RequestedPort>>,
+}
+
+impl ControlResponse {
+    pub fn ok() -> ControlResponse {
+        ControlResponse {
+            ok: true,
+            ..ControlResponse::default()
+        }
+    }
+
+    pub fn error(error: String) -> ControlResponse {
+        ControlResponse {
+            error: Some(error),
+            ..ControlResponse::default()
+        }
+    }
+}
+
+/// A session of the Host, the Container is unknown until its HELLO was accepted.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct SessionInfo {
+    pub id: u32,
+    pub hostname: Option<String>,
+    pub devcontainer: Option<String>,
+    pub version: Option<String>,
+    pub paused: bool,
+    pub ports: Vec<ForwardedPort>,
+}
+
+impl SessionInfo {
+    /// The Container like `devbox (app)`, or the session id before its HELLO was accepted.
+    pub fn container(&self) -> String {
+        match (&self.hostname, &self.devcontainer) {
+            (Some(hostname), Some(devcontainer)) => format!("{hostname} ({devcontainer})"),
+            (Some(hostname), None) => hostname.clone(),
+            (None, _) => format!("Session {}", self.id),
+        }
+    }
+
+    /// True if `container` is the id of the session, the hostname or the devcontainer.
+    pub fn matches(&self, container: &str) -> bool {
+        self.id.to_string() == container
+            || self.hostname.as_deref() == Some(container)
+            || self.devcontainer.as_deref() == Some(container)
+    }
+}
+
+/// Something that happened on the Host, sent to the subscribers of the control socket.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ControlEvent {
+    SessionOpened {
+        session: u32,
+    },
+    SessionClosed {
+        session: u32,
+    },
+    Forwarded {
+        session: u32,
+        #[serde(flatten)]
+        port: ForwardedPort,
+    },
+    Refused {
+        session: u32,
+        port: u16,
+        protocol: Protocol,
+        reason: String,
+    },
+    Closed {
+        session: u32,
+        port: u16,
+        protocol: Protocol,
+    },
+    /// Auto forwarding was paused for one session, or for all of them.
+    Paused {
+        session: Option<u32>,
+    },
+    Resumed {
+        session: Option<u32>,
+    },
+}
+
+impl ControlEvent {
+    fn of_change(session: u32, change: PortChange) -> ControlEvent {
+        match change {
+            PortChange::Forwarded(port) => ControlEvent::Forwarded { session, port },
+            PortChange::Refused {
+                port,
+                protocol,
+                reason,
+            } => ControlEvent::Refused {
+                session,
+                port,
+                protocol,
+                reason,
+            },
+            PortChange::Closed { port, protocol } => ControlEvent::Closed {
+                session,
+                port,
+                protocol,
+            },
+        }
+    }
+}
+
+/// The sessions of the Host, which are inspected and changed through the control socket.
+#[derive(Clone, Default)]
+pub struct Control {
+    sessions: Arc<Mutex<BTreeMap<u32, SessionHandle>>>,
+    subscribers: Arc<Mutex<Vec<Sender<ControlEvent>>>>,
+    /// Auto forwarding is paused for all sessions, including the ones still to come.
+    paused: Arc<AtomicBool>,
+}
+
+impl Control {
+    pub fn new() -> Control {
+        Control::default()
+    }
+
+    /// Adds the session `id`, the changes of its ports are published to the subscribers.
+    pub fn add_session(&self, id: u32, session: SessionHandle) {
+        session.pause(self.paused.load(Ordering::SeqCst));
+        let changes = session.watch();
+        self.sessions.lock().unwrap().insert(id, session);
+        self.publish(ControlEvent::SessionOpened { session: id });
+        let control = self.clone();
+        thread::spawn(move || {
+            for change in changes.iter() {
+                control.publish(ControlEvent::of_change(id, change));
+            }
+        });
+    }
+
+    pub fn remove_session(&self, id: u32) {
+        if self.sessions.lock().unwrap().remove(&id).is_some() {
+            self.publish(ControlEvent::SessionClosed { session: id });
+        }
+    }
+
+    /// The running sessions, ordered by their id.
+    pub fn sessions(&self) -> Vec<SessionHandle> {
+        self.sessions.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Receives every event from now on.
+    pub fn subscribe(&self) -> Receiver<ControlEvent> {
+        let (sender, receiver) = channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn publish(&self, event: ControlEvent) {
+        // Subscribers, which disconnected, are dropped.
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    fn session(&self, id: u32) -> Result<SessionHandle, String> {
+        match self.sessions.lock().unwrap().get(&id) {
+            Some(session) => Ok(session.clone()),
+            None => Err(format!("ERROR: Session {id} does not exist")),
+        }
+    }
+
+    /// Pauses or resumes auto forwarding of the session `id`, or of all sessions.
+    fn pause(&self, id: Option<u32>, paused: bool) -> Result<(), String> {
+        match id {
+            Some(id) => self.session(id)?.pause(paused),
+            None => {
+                self.paused.store(paused, Ordering::SeqCst);
+                for session in self.sessions() {
+                    session.pause(paused);
+                }
+            }
+        }
+        self.publish(match paused {
+            true => ControlEvent::Paused { session: id },
+            false => ControlEvent::Resumed { session: id },
+        });
+        Ok(())
+    }
+
+    fn session_infos(&self) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap().clone();
+        sessions
+            .into_iter()
+            .map(|(id, session)| {
+                let peer = session.peer();
+                SessionInfo {
+                    id,
+                    hostname: peer.as_ref().map(|peer| peer.hostname.clone()),
+                    devcontainer: peer.as_ref().and_then(|peer| peer.devcontainer.clone()),
+                    version: peer.map(|peer| peer.version),
+                    paused: session.paused(),
+                    ports: session.ports(),
+                }
+            })
+            .collect()
+    }
+
+    /// Answers every request, except the streams of `Subscribe` and `Logs`, which are handled by
+    /// the connection.
+    pub fn handle(&self, request: ControlRequest) -> ControlResponse {
+        let result = match request {
+            ControlRequest::Status => Ok(ControlResponse {
+                version: Some(env!("CARGO_PKG_VERSION").to_string()),
+                paused: Some(self.paused.load(Ordering::SeqCst)),
+                sessions: Some(self.session_infos()),
+                ..ControlResponse::ok()
+            }),
+            ControlRequest::List => Ok(ControlResponse {
+                sessions: Some(self.session_infos()),
+                ..ControlResponse::ok()
+            }),
+            ControlRequest::Forward {
+                session,
+                port,
+                protocol,
+                label,
+                host_port,
+            } => self
+                .session(session)
+                .and_then(|session| {
+                    let request = PortRequest {
+                        label,
+                        host_port,
+                        ..PortRequest::default()
+                    };
+                    session.forward(port, protocol, request)
+                })
+                .map(|address| ControlResponse {
+                    address: Some(address),
+                    ..ControlResponse::ok()
+                }),
+            ControlRequest::Unforward {
+                session,
+                port,
+                protocol,
+            } => self
+                .session(session)
+                .and_then(|session| session.unforward(port, protocol))
+                .map(|_| ControlResponse::ok()),
+            ControlRequest::Pause { session } => {
+                self.pause(session, true).map(|_| ControlResponse::ok())
+            }
+            ControlRequest::Resume { session } => {
+                self.pause(session, false).map(|_| ControlResponse::ok())
+            }
+            ControlRequest::Logs { follow: false } => Ok(ControlResponse {
+                log: Some(recent_log()),
+                ..ControlResponse::ok()
+            }),
+            ControlRequest::Subscribe | ControlRequest::Logs { follow: true } => {
+                Err("ERROR: Streams are only available on the control socket".to_string())
+            }
+        };
+        result.unwrap_or_else(ControlResponse::error)
+    }
+
+    /// Answers the clients of `listener`, each one in its own thread.
+    pub fn serve(&self, listener: UnixListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let control = self.clone();
+                    thread::spawn(move || control.handle_client(stream));
+                }
+                Err(err) => error!("ERROR: Unable to accept control connection\n{err}"),
+            }
+        }
+    }
+
+    fn handle_client(&self, stream: UnixStream) {
+        let mut writer = match stream.try_clone() {
+            Ok(writer) => writer,
+            Err(err) => {
+                error!("ERROR: Unable to answer control connection\n{err}");
+                return;
+            }
+        };
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let response = match serde_json::from_str::<ControlRequest>(&line) {
+                Ok(ControlRequest::Subscribe) => {
+                    let events = self.subscribe();
+                    if write_line(&mut writer, &ControlResponse::ok()).is_err() {
+                        return;
+                    }
+                    for event in events.iter() {
+                        if write_line(&mut writer, &event).is_err() {
+                            break;
+                        }
+                    }
+                    return;
+                }
+                Ok(ControlRequest::Logs { follow: true }) => {
+                    // Lines printed while the recent ones are sent are not missed.
+                    let lines = follow_log();
+                    let response = self.handle(ControlRequest::Logs { follow: false });
+                    if write_line(&mut writer, &response).is_err() {
+                        return;
+                    }
+                    for line in lines.iter() {
+                        if write_line(&mut writer, &line).is_err() {
+                            break;
+                        }
+                    }
+                    return;
+                }
+                Ok(request) => self.handle(request),
+                Err(err) => ControlResponse::error(format!("ERROR: Invalid request\n{err}")),
+            };
+            if write_line(&mut writer, &response).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Answers every request of the clients of `listener` with `handle`, each client in its own thread.
+pub fn serve_requests<R, F>(listener: UnixListener, handle: F)
+where
+    R: DeserializeOwned,
+    F: Fn(R) -> ControlResponse + Clone + Send + 'static,
+{
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("ERROR: Unable to accept control connection\n{err}");
+                continue;
+            }
+        };
+        let handle = handle.clone();
+        thread::spawn(move || {
+            let mut writer = match stream.try_clone() {
+                Ok(writer) => writer,
+                Err(_) => return,
+            };
+            for line in BufReader::new(stream).lines().map_while(Result::ok) {
+                let response = match serde_json::from_str::<R>(&line) {
+                    Ok(request) => handle(request),
+                    Err(err) => ControlResponse::error(format!("ERROR: Invalid request\n{err}")),
+                };
+                if write_line(&mut writer, &response).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+fn write_line<T: Serialize>(stream: &mut UnixStream, value: &T) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    stream.write_all(&line)
+}
+
+/// A connection to the control socket of a running Host.
+pub struct ControlClient {
+    stream: BufReader<UnixStream>,
+}
+
+impl ControlClient {
+    pub fn connect(path: &Path) -> Result<ControlClient, String> {
+        match UnixStream::connect(path) {
+            Ok(stream) => Ok(ControlClient {
+                stream: BufReader::new(stream),
+            }),
+            Err(err) => Err(format!(
+                "ERROR: Nothing is listening on the control socket {}, is autoForward running?\n{err}",
+                path.display()
+            )),
+        }
+    }
+
+    /// Sends `request`, a response which is not ok is returned as its error.
+    pub fn request<R: Serialize>(&mut self, request: &R) -> Result<ControlResponse, String> {
+        write_line(self.stream.get_mut(), request).map_err(|err| {
+            format!("ERROR: Unable to send the request to the control socket\n{err}")
+        })?;
+        let response: ControlResponse = self
+            .receive()?
+            .ok_or("ERROR: The control socket was closed")?;
+        match response.ok {
+            true => Ok(response),
+            false => Err(response
+                .error
+                .unwrap_or("ERROR: The request was refused".to_string())),
+        }
+    }
+
+    /// The next line of a stream, `None` once the connection was closed.
+    pub fn receive<T: DeserializeOwned>(&mut self) -> Result<Option<T>, String> {
+        let mut line = String::new();
+        match self.stream.read_line(&mut line) {
+            Ok(0) => Ok(None),
+            Ok(_) => serde_json::from_str(&line)
+                .map(Some)
+                .map_err(|err| format!("ERROR: Invalid answer on the control socket\n{err}")),
+            Err(err) => Err(format!(
+                "ERROR: Lost the connection to the control socket\n{err}"
+            )),
+        }
+    }
+}
+
+/// Binds the control socket at `path`, with the permissions `mode` like `0o600`.
+///
+/// A socket left behind by a Program, which did not shut down, is replaced.
+pub fn bind_control(path: &Path, mode: u32) -> Result<UnixListener, String> {
+    bind_unix(path, mode, "control socket")
+}
+
+/// Binds the Unix socket `name` at `path`, like `bind_control` does for the control socket.
+///
+/// Only sockets are replaced, any other file at `path` is most likely a mistyped path.
+pub fn bind_unix(path: &Path, mode: u32, name: &str) -> Result<UnixListener, String> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(format!(
+                "ERROR: {} is no socket, which could be replaced by the {name}",
+                path.display()
+            ));
+        }
+        if UnixStream::connect(path).is_ok() {
+            return Err(format!(
+                "ERROR: Another Program is listening on the {name} {}",
+                path.display()
+            ));
+        }
+        fs::remove_file(path).map_err(|err| {
+            format!(
+                "ERROR: Unable to remove the {name} {}\n{err}",
+                path.display()
+            )
+        })?;
+    }
+    // Other users must not connect between creating the socket and restricting it.
+    // SAFETY: umask only takes an integer and always succeeds.
+    let umask = unsafe { libc::umask((!mode & 0o077) as libc::mode_t) };
+    let bound = UnixListener::bind(path);
+    // SAFETY: see above, the previous mask is restored.
+    unsafe { libc::umask(umask) };
+    let listener = bound.map_err(|err| {
+        format!(
+            "ERROR: Unable to create the {name} {}\n{err}",
+            path.display()
+        )
+    })?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(|err| {
+        format!(
+            "ERROR: Unable to restrict the {name} {}\n{err}",
+            path.display()
+        )
+    })?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod test_control {
+    use super::*;
+    use crate::test_util::free_port;
+    use crate::{
+        create_message, read_message, send_hello, send_message, Function, Hello, Multiplexer,
+        PortReply,
+    };
+    use std::env;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    fn connect(path: &Path) -> BufReader<UnixStream> {
+        let stream = UnixStream::connect(path).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        BufReader::new(stream)
+    }
+
+    fn request(client: &mut BufReader<UnixStream>, request: &str) -> String {
+        let request = format!("{request}\n");
+        client.get_mut().write_all(request.as_bytes()).unwrap();
+        read_line(client)
+    }
+
+    fn read_line(client: &mut BufReader<UnixStream>) -> String {
+        let mut line = String::new();
+        client.read_line(&mut line).unwrap();
+        line
+    }
+
+    fn reply(container: &TcpStream) -> PortReply {
+        let created = read_message(container).unwrap().unwrap();
+        assert_eq!(Function::CreatedTcp, created.header.function);
+        PortReply::decode(&created.body).unwrap()
+    }
+
+    #[test]
+    fn parse_requests() {
+        assert_eq!(
+            ControlRequest::Forward {
+                session: 1,
+                port: 8080,
+                protocol: Protocol::TCP,
+                label: None,
+                host_port: None
+            },
+            serde_json::from_str(r#"{"command": "forward", "session": 1, "port": 8080}"#).unwrap()
+        );
+        assert_eq!(
+            ControlRequest::Pause { session: None },
+            serde_json::from_str(r#"{"command": "pause"}"#).unwrap()
+        );
+        let unforward = r#"{"command": "unforward", "session": 2, "port": 53, "protocol": "udp"}"#;
+        assert_eq!(
+            ControlRequest::Unforward {
+                session: 2,
+                port: 53,
+                protocol: Protocol::UDP
+            },
+            serde_json::from_str(unforward).unwrap()
+        );
+        assert!(serde_json::from_str::<ControlRequest>(r#"{"command": "reboot"}"#).is_err());
+        let colour = r#"{"command": "pause", "session": 1, "colour": "red"}"#;
+        assert!(serde_json::from_str::<ControlRequest>(colour).is_err());
+        assert_eq!(
+            r#"{"event":"closed","session":1,"port":53,"protocol":"udp"}"#,
+            serde_json::to_string(&ControlEvent::Closed {
+                session: 1,
+                port: 53,
+                protocol: Protocol::UDP
+            })
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn serve_agent_requests() {
+        let path = env::temp_dir().join(format!("auto_forward-agent-{}.sock", std::process::id()));
+        let listener = bind_control(&path, 0o666).unwrap();
+        thread::spawn(move || {
+            serve_requests(listener, |request| match request {
+                AgentRequest::Forward { port: 0, .. } => {
+                    ControlResponse::error("ERROR: Port 0 can not be forwarded".to_string())
+                }
+                _ => ControlResponse::ok(),
+            })
+        });
+        let mut client = ControlClient::connect(&path).unwrap();
+        assert!(client.request(&AgentRequest::List).is_ok());
+        let forward = AgentRequest::Forward {
+            port: 0,
+            protocol: Protocol::TCP,
+            host_port: None,
+        };
+        assert_eq!(
+            Err("ERROR: Port 0 can not be forwarded".to_string()),
+            client.request(&forward)
+        );
+        assert!(client.request(&ControlRequest::Status).is_err());
+        assert_eq!(
+            0o666,
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn keep_other_files() {
+        let path = env::temp_dir().join(format!("auto_forward-notes-{}.txt", std::process::id()));
+        fs::write(&path, "notes").unwrap();
+        assert!(bind_control(&path, 0o600).is_err());
+        assert_eq!("notes", fs::read_to_string(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn control_socket() {
+        let path = env::temp_dir().join(format!("auto_forward-test-{}.sock", std::process::id()));
+        let control = Control::new();
+        let listener = bind_control(&path, 0o600).unwrap();
+        assert!(bind_control(&path, 0o600).is_err());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        let serving = control.clone();
+        thread::spawn(move || serving.serve(listener));
+        let mut client = connect(&path);
+        let mut subscriber = connect(&path);
+        assert_eq!(
+            "{\"ok\":true}\n",
+            request(&mut subscriber, r#"{"command": "subscribe"}"#)
+        );
+
+        let host = TcpListener::bind("127.0.0.1:0").unwrap();
+        let container = TcpStream::connect(host.local_addr().unwrap()).unwrap();
+        let (stream, _) = host.accept().unwrap();
+        let multi = Multiplexer::new(stream);
+        control.add_session(1, multi.handle());
+        thread::spawn(move || multi.run());
+        send_hello(&container, &Hello::new("devbox".to_string(), None)).unwrap();
+
+        // Requests of the Container are refused, manual forwards are not.
+        let response = request(&mut client, r#"{"command": "pause"}"#);
+        assert_eq!("{\"ok\":true}\n", response);
+        let (refused, manual) = (free_port(), free_port());
+        let mut writer = container.try_clone().unwrap();
+        send_message(
+            &mut writer,
+            create_message(refused, Function::CreateTcp, Vec::new()),
+        )
+        .unwrap();
+        assert!(matches!(reply(&container), PortReply::Refused(_)));
+        let response: ControlResponse = serde_json::from_str(&request(
+            &mut client,
+            &format!(
+                r#"{{"command": "forward", "session": 1, "port": {manual}, "label": "docs"}}"#
+            ),
+        ))
+        .unwrap();
+        let address = response.address.unwrap();
+        assert_eq!(PortReply::Forwarded(address), reply(&container));
+
+        let response: ControlResponse =
+            serde_json::from_str(&request(&mut client, r#"{"command": "list"}"#)).unwrap();
+        let sessions = response.sessions.unwrap();
+        assert_eq!(1, sessions.len());
+        assert_eq!(Some("devbox".to_string()), sessions[0].hostname);
+        assert!(sessions[0].paused);
+        assert_eq!(
+            vec![(manual, address.port(), Some("docs".to_string()))],
+            sessions[0]
+                .ports
+                .iter()
+                .map(|port| (port.port, port.host_port, port.label.clone()))
+                .collect::<Vec<(u16, u16, Option<String>)>>()
+        );
+
+        let unforward = format!(r#"{{"command": "unforward", "session": 1, "port": {manual}}}"#);
+        assert_eq!("{\"ok\":true}\n", request(&mut client, &unforward));
+        let response: ControlResponse =
+            serde_json::from_str(&request(&mut client, &unforward)).unwrap();
+        assert!(!response.ok && response.error.is_some());
+        let response: ControlResponse = serde_json::from_str(&request(
+            &mut client,
+            r#"{"command": "resume", "session": 7}"#,
+        ))
+        .unwrap();
+        assert_eq!(
+            Some("ERROR: Session 7 does not exist".to_string()),
+            response.error
+        );
+        let response: ControlResponse =
+            serde_json::from_str(&request(&mut client, "no json")).unwrap();
+        assert!(!response.ok);
+
+        let events = [
+            ControlEvent::SessionOpened { session: 1 },
+            ControlEvent::Paused { session: None },
+        ];
+        for event in events {
+            let line = read_line(&mut subscriber);
+            assert_eq!(event, serde_json::from_str(&line).unwrap());
+        }
+        let line = read_line(&mut subscriber);
+        assert!(matches!(
+            serde_json::from_str(&line).unwrap(),
+            ControlEvent::Refused { port, .. } if port == refused
+        ));
+        let line = read_line(&mut subscriber);
+        assert!(matches!(
+            serde_json::from_str(&line).unwrap(),
+            ControlEvent::Forwarded { port, .. } if port.host_port == address.port()
+        ));
+        let line = read_line(&mut subscriber);
+        assert_eq!(
+            ControlEvent::Closed {
+                session: 1,
+                port: manual,
+                protocol: Protocol::TCP
+            },
+            serde_json::from_str(&line).unwrap()
+        );
+
+        let mut client = ControlClient::connect(&path).unwrap();
+        let status = client.request(&ControlRequest::Status).unwrap();
+        assert_eq!(Some(true), status.paused);
+        assert_eq!(1, status.sessions.unwrap().len());
+        let error = client.request(&ControlRequest::Resume { session: Some(7) });
+        assert_eq!(Err("ERROR: Session 7 does not exist".to_string()), error);
+        let mut logs = ControlClient::connect(&path).unwrap();
+        let timeout = Some(Duration::from_secs(5));
+        logs.stream.get_ref().set_read_timeout(timeout).unwrap();
+        logs.request(&ControlRequest::Logs { follow: true })
+            .unwrap();
+        let host_port = free_port();
+        let forward = ControlRequest::Forward {
+            session: 1,
+            port: manual,
+            protocol: Protocol::TCP,
+            label: None,
+            host_port: Some(host_port),
+        };
+        let address = client.request(&forward).unwrap().address.unwrap();
+        assert_eq!(host_port, address.port());
+        let forwarding = format!("INFO: Forwarding TCP Port {manual} to Host Port {host_port}");
+        while logs.receive::<String>().unwrap().unwrap() != forwarding {}
+        fs::remove_file(&path).unwrap();
+    }
+}
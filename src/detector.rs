@@ -0,0 +1,401 @@
+use crate::{scan_proc_net, ListenPort, Protocol};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::path::PathBuf;
+use std::process::Command;
+use std::str;
+use std::time::Duration;
+
+/// Interval between two scans of the lsof and /proc detectors.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Interval of the netlink detector, right after a port was opened or closed.
+pub const FAST_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Interval the netlink detector slows down to, while no port changes.
+pub const SLOW_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A strategy to find the listening sockets inside the Container.
+pub trait PortDetector {
+    /// All sockets listening right now.
+    ///
+    /// An error means the sockets are unknown, which must not be taken as all ports being closed.
+    fn detect(&mut self) -> Result<Vec<ListenPort>, io::Error>;
+
+    /// Time to wait before the next call of `detect`.
+    fn interval(&self) -> Duration {
+        POLL_INTERVAL
+    }
+}
+
+/// Runs `lsof -i -P -n`, which works everywhere lsof is installed.
+pub struct Lsof {
+    pub interval: Duration,
+}
+
+impl PortDetector for Lsof {
+    fn detect(&mut self) -> Result<Vec<ListenPort>, io::Error> {
+        let output = Command::new("lsof")
+            .arg("-i")
+            .arg("-P")
+            .arg("-n")
+            .output()?;
+        // lsof exits with 1 if there are no sockets at all.
+        if !output.status.success() && !output.stderr.is_empty() {
+            return Err(io::Error::other(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        let stdout = str::from_utf8(&output.stdout)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+        Ok(parse_lsof(stdout))
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+/// Reads the socket tables of procfs mounted at `root`, usually `/proc`.
+pub struct ProcNet {
+    pub root: PathBuf,
+    pub interval: Duration,
+}
+
+impl PortDetector for ProcNet {
+    fn detect(&mut self) -> Result<Vec<ListenPort>, io::Error> {
+        if !self.root.join("net").join("tcp").exists() {
+            return Err(ErrorKind::NotFound.into());
+        }
+        Ok(scan_proc_net(&self.root))
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+/// Asks the kernel for the listening sockets, which is cheap enough to poll several times a
+/// second while ports are changing.
+#[cfg(target_os = "linux")]
+pub struct SockDiag {
+    interval: Duration,
+    last: Vec<ListenPort>,
+    /// Process of every socket inode, see `scan_sock_diag`.
+    owners: HashMap<u32, String>,
+}
+
+#[cfg(target_os = "linux")]
+impl SockDiag {
+    pub fn new() -> SockDiag {
+        SockDiag {
+            interval: FAST_POLL_INTERVAL,
+            last: Vec::new(),
+            owners: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Default for SockDiag {
+    fn default() -> SockDiag {
+        SockDiag::new()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl PortDetector for SockDiag {
+    fn detect(&mut self) -> Result<Vec<ListenPort>, io::Error> {
+        let ports = crate::scan_sock_diag(&mut self.owners)?;
+        self.interval = match ports == self.last {
+            true => (self.interval * 2).min(SLOW_POLL_INTERVAL),
+            false => FAST_POLL_INTERVAL,
+        };
+        self.last = ports.clone();
+        Ok(ports)
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+/// A fixed list of ports, which are forwarded whether they are listening or not.
+pub struct StaticPorts {
+    pub ports: Vec<ListenPort>,
+}
+
+impl StaticPorts {
+    /// Parses a comma separated list like `8080,5353/udp`, TCP is the default protocol.
+    pub fn parse(list: &str) -> Result<StaticPorts, String> {
+        let mut ports = Vec::new();
+        for entry in list.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let (port, protocol) = match entry.split_once('/') {
+                Some((port, protocol)) => (port, Protocol::decode(protocol)?),
+                None => (entry, Protocol::TCP),
+            };
+            let port = port
+                .parse::<u16>()
+                .map_err(|_| format!("ERROR: {entry} is not a valid Port"))?;
+            ports.push(ListenPort {
+                port,
+                ip: "localhost".to_string(),
+                protocol,
+                app: "static".to_string(),
+            });
+        }
+        Ok(StaticPorts { ports })
+    }
+}
+
+impl PortDetector for StaticPorts {
+    fn detect(&mut self) -> Result<Vec<ListenPort>, io::Error> {
+        Ok(self.ports.clone())
+    }
+}
+
+/// Creates the detector called `name`, or the first one working on this system.
+///
+/// The order is netlink, /proc and lsof, `static` forwards the ports listed in `ports`.
+/// `poll_interval` is the time between two scans of the lsof and /proc detectors.
+pub fn port_detector(
+    name: Option<&str>,
+    ports: &[String],
+    poll_interval: Duration,
+) -> Result<Box<dyn PortDetector + Send>, String> {
+    let proc = ProcNet {
+        root: PathBuf::from("/proc"),
+        interval: poll_interval,
+    };
+    let lsof = Lsof {
+        interval: poll_interval,
+    };
+    match name {
+        Some("lsof") => return Ok(Box::new(lsof)),
+        Some("proc") => return Ok(Box::new(proc)),
+        #[cfg(target_os = "linux")]
+        Some("netlink") => return Ok(Box::new(SockDiag::new())),
+        Some("static") => return Ok(Box::new(StaticPorts::parse(&ports.join(","))?)),
+        Some(name) => {
+            return Err(format!(
+                "ERROR: Unknown port detector {name}, use netlink, proc, lsof or static"
+            ))
+        }
+        None => {}
+    }
+    #[cfg(target_os = "linux")]
+    if crate::scan_sock_diag(&mut HashMap::new()).is_ok() {
+        return Ok(Box::new(SockDiag::new()));
+    }
+    if proc.root.join("net").join("tcp").exists() {
+        return Ok(Box::new(proc));
+    }
+    // Without procfs, e.g. on macOS, lsof is the only way to find the listening sockets.
+    Ok(Box::new(lsof))
+}
+
+/// Change of the listening sockets between two scans.
+#[derive(Debug, PartialEq, Clone)]
+pub enum PortEvent {
+    Added(ListenPort),
+    Removed(ListenPort),
+}
+
+/// Turns the scans of any detector into events, ports are identified by port and protocol.
+#[derive(Default)]
+pub struct PortDiffer {
+    known: HashMap<(u16, Protocol), ListenPort>,
+}
+
+impl PortDiffer {
+    pub fn new() -> PortDiffer {
+        PortDiffer::default()
+    }
+
+    /// Compares `ports` with the previous scan.
+    ///
+    /// A port listening on several addresses is only reported once, a port changing its address
+    /// or process is removed and added again.
+    pub fn update(&mut self, ports: Vec<ListenPort>) -> Vec<PortEvent> {
+        let mut current: HashMap<(u16, Protocol), ListenPort> = HashMap::new();
+        for port in ports {
+            current
+                .entry((port.port, port.protocol.clone()))
+                .or_insert(port);
+        }
+        let mut events = Vec::new();
+        let mut removed = self
+            .known
+            .iter()
+            .filter(|(key, port)| current.get(key) != Some(port))
+            .map(|(_, port)| port.clone())
+            .collect::<Vec<ListenPort>>();
+        removed.sort_by_key(|port| port.port);
+        events.extend(removed.into_iter().map(PortEvent::Removed));
+        let mut added = current
+            .iter()
+            .filter(|(key, port)| self.known.get(key) != Some(port))
+            .map(|(_, port)| port.clone())
+            .collect::<Vec<ListenPort>>();
+        added.sort_by_key(|port| port.port);
+        events.extend(added.into_iter().map(PortEvent::Added));
+        self.known = current;
+        events
+    }
+}
+
+/// Parses the output of `lsof -i -P -n`.
+fn parse_lsof(stdout: &str) -> Vec<ListenPort> {
+    let mut results = stdout.split('\n').collect::<Vec<&str>>();
+    let header = results
+        .remove(0)
+        .split(' ')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<&str>>();
+    // Without any sockets, lsof prints nothing at all.
+    if header.is_empty() {
+        return Vec::new();
+    }
+    let mut table = results
+        .into_iter()
+        .map(|row| {
+            row.split(' ')
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .collect::<Vec<&str>>()
+        })
+        .filter(|row| row.len() == header.len() || row.len() == header.len() + 1)
+        .collect::<Vec<Vec<&str>>>();
+    // TCP sockets are listening in the LISTEN state, while UDP sockets have no state
+    // and are only bound, as long as they are not connected to a peer.
+    table.retain(|r| match r.last() {
+        Some(l) if r.len() == header.len() + 1 => *l == "(LISTEN)",
+        Some(l) => r.contains(&"UDP") && !l.contains("->"),
+        None => false,
+    });
+    let mut port_list: Vec<ListenPort> = Vec::new();
+    for row in table {
+        let port_str: &str = match row.get(header.len() - 1) {
+            Some(port) => port.split(':').next_back().unwrap_or(port),
+            None => continue,
+        };
+        let port = match port_str.parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => continue,
+        };
+        let port_str = row.get(header.len() - 1).unwrap_or(&"a");
+        let mut ip: String = match row.get(header.len() - 1) {
+            Some(port) => port
+                .split(':')
+                .take(port_str.split(':').count() - 1)
+                .collect::<Vec<&str>>()
+                .join(":"),
+            None => continue,
+        };
+        if ip == "*" {
+            ip = "localhost".to_string();
+        }
+        let proto = match Protocol::decode(
+            row[header
+                .iter()
+                .position(|x| *x == "NODE")
+                .unwrap_or(header.len() - 2)],
+        ) {
+            Ok(protocol) => protocol,
+            Err(_) => continue,
+        };
+        let app = match row.first() {
+            Some(app) => app.to_string(),
+            None => "Unkown".to_string(),
+        };
+        let item = ListenPort {
+            port,
+            ip,
+            protocol: proto,
+            app,
+        };
+        port_list.push(item);
+    }
+    port_list
+}
+
+#[cfg(test)]
+mod test_detector {
+    use super::*;
+    use crate::test_util::listen_port;
+
+    #[test]
+    fn parse_lsof_output() {
+        let output = "\
+COMMAND   PID USER   FD   TYPE DEVICE SIZE/OFF NODE NAME
+node      100 dev   20u  IPv4  41501      0t0  TCP *:3000 (LISTEN)
+node      100 dev   21u  IPv4  41502      0t0  TCP 127.0.0.1:3000->127.0.0.1:51000 (ESTABLISHED)
+postgres  200 dev    6u  IPv6  41503      0t0  TCP [::1]:5432 (LISTEN)
+dnsmasq   300 dev    4u  IPv4  41504      0t0  UDP *:53
+curl      400 dev    5u  IPv4  41505      0t0  UDP 127.0.0.1:40000->127.0.0.1:53
+";
+        let ports = parse_lsof(output);
+        assert_eq!(
+            vec![
+                listen_port(3000, Protocol::TCP, "localhost", "node"),
+                listen_port(5432, Protocol::TCP, "[::1]", "postgres"),
+                listen_port(53, Protocol::UDP, "localhost", "dnsmasq"),
+            ],
+            ports
+        );
+    }
+
+    #[test]
+    fn parse_static_ports() {
+        let ports = StaticPorts::parse("8080, 5353/udp,").unwrap().ports;
+        assert_eq!(
+            vec![
+                listen_port(8080, Protocol::TCP, "localhost", "static"),
+                listen_port(5353, Protocol::UDP, "localhost", "static")
+            ],
+            ports
+        );
+        assert!(StaticPorts::parse("http").is_err());
+        assert!(StaticPorts::parse("80/sctp").is_err());
+    }
+
+    #[test]
+    fn unknown_detector() {
+        assert!(port_detector(Some("magic"), &[], POLL_INTERVAL).is_err());
+        let ports = vec!["8080".to_string()];
+        let mut detector = port_detector(Some("static"), &ports, POLL_INTERVAL).unwrap();
+        assert_eq!(1, detector.detect().unwrap().len());
+    }
+
+    #[test]
+    fn differ_events() {
+        let mut differ = PortDiffer::new();
+        let web = listen_port(3000, Protocol::TCP, "localhost", "node");
+        let dns = listen_port(53, Protocol::UDP, "localhost", "dnsmasq");
+        assert_eq!(
+            vec![PortEvent::Added(dns.clone()), PortEvent::Added(web.clone())],
+            differ.update(vec![web.clone(), dns.clone()])
+        );
+        assert!(differ.update(vec![dns.clone(), web.clone()]).is_empty());
+
+        // The same port on a second address is no new port.
+        let web6 = ListenPort {
+            ip: "[::1]".to_string(),
+            ..web.clone()
+        };
+        assert!(differ
+            .update(vec![web.clone(), web6, dns.clone()])
+            .is_empty());
+
+        let restarted = listen_port(3000, Protocol::TCP, "localhost", "deno");
+        assert_eq!(
+            vec![
+                PortEvent::Removed(dns.clone()),
+                PortEvent::Removed(web),
+                PortEvent::Added(restarted.clone())
+            ],
+            differ.update(vec![restarted])
+        );
+    }
+}
@@ -0,0 +1,312 @@
+use crate::rules::parse_range;
+use crate::{ListenPort, Protocol};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+
+/// Locations of the configuration inside a workspace folder, as defined by the devcontainer spec.
+const CONFIG_PATHS: [&str; 2] = [".devcontainer/devcontainer.json", ".devcontainer.json"];
+
+/// What happens when a port is detected, only `ignore` changes the forwarding.
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OnAutoForward {
+    Notify,
+    OpenBrowser,
+    OpenBrowserOnce,
+    OpenPreview,
+    Silent,
+    Ignore,
+}
+
+/// Attributes of a port in `portsAttributes` or `otherPortsAttributes`.
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct PortAttributes {
+    pub label: Option<String>,
+    /// Protocol of the application, `http` or `https`.
+    pub protocol: Option<String>,
+    pub on_auto_forward: Option<OnAutoForward>,
+    /// The Host has to use the same port, instead of picking a free one.
+    pub require_local_port: bool,
+    /// The Host may bind privileged ports.
+    pub elevate_if_needed: bool,
+}
+
+impl PortAttributes {
+    pub fn ignored(&self) -> bool {
+        self.on_auto_forward == Some(OnAutoForward::Ignore)
+    }
+}
+
+/// Key of `portsAttributes`, a port, a range or a regular expression for the process.
+#[derive(Debug, Clone)]
+enum PortPattern {
+    Ports(RangeInclusive<u16>),
+    Process(Regex),
+}
+
+impl PortPattern {
+    fn parse(key: &str) -> Result<PortPattern, String> {
+        match parse_range(key) {
+            Some(ports) => Ok(PortPattern::Ports(ports)),
+            None => Regex::new(key)
+                .map(PortPattern::Process)
+                .map_err(|err| format!("ERROR: \"{key}\" is no port, range or regex\n{err}")),
+        }
+    }
+
+    fn matches(&self, port: &ListenPort) -> bool {
+        match self {
+            PortPattern::Ports(ports) => ports.contains(&port.port),
+            PortPattern::Process(process) => process.is_match(&port.app),
+        }
+    }
+}
+
+/// The forwarding settings of a `devcontainer.json`, all other settings are ignored.
+#[derive(Debug, Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct Settings {
+    forward_ports: Vec<Value>,
+    ports_attributes: Map<String, Value>,
+    other_ports_attributes: Option<PortAttributes>,
+}
+
+/// Ports and port attributes of a `devcontainer.json`, see <https://containers.dev>.
+#[derive(Debug, Clone, Default)]
+pub struct DevContainer {
+    /// Ports forwarded from the start, whether they are listening or not.
+    pub forward_ports: Vec<ListenPort>,
+    ports_attributes: Vec<(PortPattern, PortAttributes)>,
+    other_ports_attributes: PortAttributes,
+}
+
+impl DevContainer {
+    /// Reads the `devcontainer.json` at `path`, or the one of the workspace folder at `path`.
+    pub fn read(path: &Path) -> Result<DevContainer, String> {
+        let file = match path.is_dir() {
+            true => CONFIG_PATHS
+                .iter()
+                .map(|config| path.join(config))
+                .find(|config| config.exists())
+                .ok_or(format!(
+                    "ERROR: The workspace {} has no devcontainer.json",
+                    path.display()
+                ))?,
+            false => path.to_path_buf(),
+        };
+        let content = fs::read_to_string(&file)
+            .map_err(|err| format!("ERROR: Unable to read {}\n{err}", file.display()))?;
+        DevContainer::parse(&content)
+            .map_err(|err| format!("ERROR: Invalid devcontainer.json {}\n{err}", file.display()))
+    }
+
+    /// Parses a `devcontainer.json`, which may contain comments and trailing commas.
+    pub fn parse(content: &str) -> Result<DevContainer, String> {
+        let settings: Settings =
+            serde_json::from_str(&strip_jsonc(content)).map_err(|err| err.to_string())?;
+        let forward_ports = settings
+            .forward_ports
+            .iter()
+            .map(parse_forward_port)
+            .collect::<Result<Vec<ListenPort>, String>>()?;
+        let mut ports_attributes = Vec::new();
+        for (key, attributes) in settings.ports_attributes {
+            let attributes = serde_json::from_value(attributes)
+                .map_err(|err| format!("ERROR: Invalid portsAttributes of \"{key}\"\n{err}"))?;
+            ports_attributes.push((PortPattern::parse(&key)?, attributes));
+        }
+        Ok(DevContainer {
+            forward_ports,
+            ports_attributes,
+            other_ports_attributes: settings.other_ports_attributes.unwrap_or_default(),
+        })
+    }
+
+    /// The only workspace folder below `root` with a `devcontainer.json`, like `/workspaces/app`.
+    pub fn find_workspace(root: &Path) -> Option<PathBuf> {
+        let mut workspaces = fs::read_dir(root)
+            .ok()?
+            .flatten()
+            .map(|workspace| workspace.path())
+            .filter(|workspace| {
+                CONFIG_PATHS
+                    .iter()
+                    .any(|config| workspace.join(config).exists())
+            });
+        match (workspaces.next(), workspaces.next()) {
+            (Some(workspace), None) => Some(workspace),
+            _ => None,
+        }
+    }
+
+    /// The attributes of the first matching key of `portsAttributes`, otherwise the ones of
+    /// `otherPortsAttributes`.
+    ///
+    /// Regular expressions are matched against the name of the process.
+    pub fn attributes(&self, port: &ListenPort) -> &PortAttributes {
+        self.ports_attributes
+            .iter()
+            .find(|(pattern, _)| pattern.matches(port))
+            .map(|(_, attributes)| attributes)
+            .unwrap_or(&self.other_ports_attributes)
+    }
+
+    /// True if `port` is listed in `forwardPorts`.
+    pub fn forwards(&self, port: &ListenPort) -> bool {
+        self.forward_ports
+            .iter()
+            .any(|forward| forward.port == port.port && forward.protocol == port.protocol)
+    }
+}
+
+/// Parses an entry of `forwardPorts`, a port like `3000` or a service like `"db:5432"`.
+fn parse_forward_port(entry: &Value) -> Result<ListenPort, String> {
+    let invalid = || format!("ERROR: {entry} of forwardPorts is no port or host:port");
+    let (ip, port) = match entry {
+        Value::Number(port) => ("localhost", port.as_u64().ok_or_else(invalid)?),
+        Value::String(entry) => match entry.split_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => ("localhost", entry.parse().map_err(|_| invalid())?),
+        },
+        _ => return Err(invalid()),
+    };
+    Ok(ListenPort {
+        port: u16::try_from(port).map_err(|_| invalid())?,
+        ip: ip.to_string(),
+        protocol: Protocol::TCP,
+        app: "Unknown".to_string(),
+    })
+}
+
+/// Turns JSON with comments into plain JSON, by removing the comments and trailing commas.
+fn strip_jsonc(content: &str) -> String {
+    let mut json = String::with_capacity(content.len());
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                json.push(c);
+                while let Some(c) = chars.next() {
+                    json.push(c);
+                    match c {
+                        '\\' => json.extend(chars.next()),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'/') => while chars.next_if(|c| *c != '\n').is_some() {},
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+                // Keeps tokens separated by a comment apart.
+                json.push(' ');
+            }
+            '}' | ']' => {
+                let end = json.trim_end().len();
+                if json[..end].ends_with(',') {
+                    json.remove(end - 1);
+                }
+                json.push(c);
+            }
+            _ => json.push(c),
+        }
+    }
+    json
+}
+
+#[cfg(test)]
+mod test_devcontainer {
+    use super::*;
+    use crate::test_util::listen_port;
+
+    const DEVCONTAINER: &str = r#"{
+        // Comments and trailing commas are allowed.
+        "name": "app",
+        "forwardPorts": [3000, "db:5432", "8080",],
+        /* Matched in order, the first key wins. */
+        "portsAttributes": {
+            "3000": { "label": "Frontend", "onAutoForward": "openBrowser" },
+            "9000-9999": { "onAutoForward": "ignore" },
+            "^java": { "label": "JVM", "requireLocalPort": true, "elevateIfNeeded": true },
+        },
+        "otherPortsAttributes": { "onAutoForward": "silent" },
+    }"#;
+
+    #[test]
+    fn strip_comments_and_trailing_commas() {
+        assert_eq!(
+            r#"{"a": [1, 2 ],  "b": "// /* kept */,]"}"#,
+            strip_jsonc("{\"a\": [1, 2, ], // comment\n \"b\": \"// /* kept */,]\"}")
+                .replace('\n', "")
+        );
+        assert_eq!("[1   ,2]", strip_jsonc("[1 /* , */ ,2]"));
+        assert_eq!(r#"["\"//"]"#, strip_jsonc(r#"["\"//"]"#));
+    }
+
+    #[test]
+    fn parse_forward_ports() {
+        let devcontainer = DevContainer::parse(DEVCONTAINER).unwrap();
+        assert_eq!(
+            vec![(3000, "localhost"), (5432, "db"), (8080, "localhost")],
+            devcontainer
+                .forward_ports
+                .iter()
+                .map(|port| (port.port, port.ip.as_str()))
+                .collect::<Vec<(u16, &str)>>()
+        );
+        assert!(devcontainer.forwards(&listen_port(8080, Protocol::TCP, "localhost", "node")));
+        assert!(!devcontainer.forwards(&listen_port(8081, Protocol::TCP, "localhost", "node")));
+        assert!(DevContainer::parse(r#"{"forwardPorts": [70000]}"#).is_err());
+        assert!(DevContainer::parse(r#"{"forwardPorts": ["db:http"]}"#).is_err());
+    }
+
+    #[test]
+    fn match_attributes() {
+        let devcontainer = DevContainer::parse(DEVCONTAINER).unwrap();
+        let frontend =
+            devcontainer.attributes(&listen_port(3000, Protocol::TCP, "localhost", "node"));
+        assert_eq!(Some("Frontend".to_string()), frontend.label);
+        assert_eq!(Some(OnAutoForward::OpenBrowser), frontend.on_auto_forward);
+        assert!(devcontainer
+            .attributes(&listen_port(9229, Protocol::TCP, "localhost", "node"))
+            .ignored());
+        // The range comes first, even though the process matches as well.
+        assert!(devcontainer
+            .attributes(&listen_port(9000, Protocol::TCP, "localhost", "java"))
+            .ignored());
+        let jvm = devcontainer.attributes(&listen_port(8081, Protocol::TCP, "localhost", "java"));
+        assert!(jvm.require_local_port && jvm.elevate_if_needed);
+        assert_eq!(
+            Some(OnAutoForward::Silent),
+            devcontainer
+                .attributes(&listen_port(4000, Protocol::TCP, "localhost", "node"))
+                .on_auto_forward
+        );
+        assert_eq!(
+            &PortAttributes::default(),
+            DevContainer::default().attributes(&listen_port(
+                3000,
+                Protocol::TCP,
+                "localhost",
+                "node"
+            ))
+        );
+        assert!(DevContainer::parse(r#"{"portsAttributes": {"(": {}}}"#).is_err());
+        assert!(
+            DevContainer::parse(r#"{"portsAttributes": {"80": {"onAutoForward": "never"}}}"#)
+                .is_err()
+        );
+    }
+}
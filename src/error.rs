@@ -0,0 +1,71 @@
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong while reading a frame from the other side.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The function byte is not known to this version, the body of the frame was skipped.
+    UnknownFunction(u8),
+    /// The connection ended in the middle of a header.
+    TruncatedHeader {
+        received: usize,
+    },
+    /// The connection ended before the announced body was complete.
+    TruncatedBody {
+        expected: u32,
+        received: usize,
+    },
+    /// The announced body exceeds `MAX_MESSAGE_SIZE`, so the frame is not read at all.
+    OversizedFrame {
+        size: u32,
+        limit: u32,
+    },
+    Io(io::Error),
+}
+
+impl ProtocolError {
+    /// Only an unknown function leaves the stream at a frame boundary, every other error
+    /// means the framing of the connection can not be trusted anymore.
+    pub fn is_recoverable(&self) -> bool {
+        matches!(self, ProtocolError::UnknownFunction(_))
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::UnknownFunction(byte) => {
+                write!(f, "Unknown Function {byte:#010b}, skipped the Message")
+            }
+            ProtocolError::TruncatedHeader { received } => {
+                write!(f, "Connection closed after {received} Bytes of the Header")
+            }
+            ProtocolError::TruncatedBody { expected, received } => write!(
+                f,
+                "Connection closed after {received} of {expected} Bytes of the Body"
+            ),
+            ProtocolError::OversizedFrame { size, limit } => {
+                write!(
+                    f,
+                    "Message of {size} Bytes exceeds the limit of {limit} Bytes"
+                )
+            }
+            ProtocolError::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(err: io::Error) -> ProtocolError {
+        ProtocolError::Io(err)
+    }
+}
@@ -0,0 +1,436 @@
+use crate::{create_message, generate_token, read_message, respond, send_message, verify};
+use crate::{Function, Message};
+use std::env;
+use std::fmt;
+use std::io::{Read, Write};
+use std::process::Command;
+use std::str;
+
+/// Version of the wire protocol spoken by this build.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Oldest protocol version this build is able to downgrade to.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+
+/// Features of the protocol, which are optional for the peer.
+pub const CAPABILITIES: [&str; 5] = ["tcp", "udp", "close", "created", "reverse"];
+
+/// The first message of every session, sent by the Container and answered by the Host.
+///
+/// The body consists of `key=value` lines, unknown keys are ignored to stay forward compatible.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Hello {
+    pub protocol: u8,
+    pub version: String,
+    pub hostname: String,
+    pub devcontainer: Option<String>,
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    pub fn new(hostname: String, devcontainer: Option<String>) -> Hello {
+        Hello {
+            protocol: PROTOCOL_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            hostname,
+            devcontainer,
+            capabilities: CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = format!(
+            "protocol={}\nversion={}\nhostname={}\ncapabilities={}\n",
+            self.protocol,
+            self.version,
+            self.hostname,
+            self.capabilities.join(",")
+        );
+        if let Some(devcontainer) = &self.devcontainer {
+            body.push_str(&format!("devcontainer={devcontainer}\n"));
+        }
+        body.into_bytes()
+    }
+
+    pub fn decode(body: &[u8]) -> Result<Hello, String> {
+        let body = str::from_utf8(body).map_err(|_| "ERROR: HELLO is not valid UTF-8")?;
+        let mut protocol = None;
+        let mut hello = Hello {
+            protocol: 0,
+            version: "unknown".to_string(),
+            hostname: "unknown".to_string(),
+            devcontainer: None,
+            capabilities: Vec::new(),
+        };
+        for line in body.lines() {
+            let (key, value) = match line.split_once('=') {
+                Some(entry) => entry,
+                None => continue,
+            };
+            match key {
+                "protocol" => protocol = value.parse::<u8>().ok(),
+                "version" => hello.version = value.to_string(),
+                "hostname" => hello.hostname = value.to_string(),
+                "devcontainer" => hello.devcontainer = Some(value.to_string()),
+                "capabilities" => {
+                    hello.capabilities = value
+                        .split(',')
+                        .filter(|c| !c.is_empty())
+                        .map(|c| c.to_string())
+                        .collect()
+                }
+                _ => {}
+            }
+        }
+        hello.protocol = protocol.ok_or("ERROR: HELLO does not contain a protocol version")?;
+        Ok(hello)
+    }
+
+    /// Identifies the Container across its sessions, as long as it is not recreated.
+    pub fn identity(&self) -> String {
+        match &self.devcontainer {
+            Some(devcontainer) => format!("{}/{devcontainer}", self.hostname),
+            None => self.hostname.clone(),
+        }
+    }
+
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Agrees on the highest protocol version and the capabilities both sides support.
+    pub fn negotiate(&self, remote: &Hello) -> Result<Hello, String> {
+        let protocol = self.protocol.min(remote.protocol);
+        if protocol < MIN_PROTOCOL_VERSION {
+            return Err(format!(
+                "ERROR: Protocol version {} of autoForward {} is not supported, at least version {MIN_PROTOCOL_VERSION} is required",
+                remote.protocol, remote.version
+            ));
+        }
+        Ok(Hello {
+            protocol,
+            version: self.version.clone(),
+            hostname: self.hostname.clone(),
+            devcontainer: self.devcontainer.clone(),
+            capabilities: self
+                .capabilities
+                .iter()
+                .filter(|c| remote.supports(c))
+                .cloned()
+                .collect(),
+        })
+    }
+}
+
+impl fmt::Display for Hello {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.hostname)?;
+        if let Some(devcontainer) = &self.devcontainer {
+            write!(f, " ({devcontainer})")?;
+        }
+        write!(
+            f,
+            ", autoForward {}, protocol {}, capabilities: {}",
+            self.version,
+            self.protocol,
+            self.capabilities.join(",")
+        )
+    }
+}
+
+/// Best effort name of the machine, used to identify a session.
+pub fn local_hostname() -> String {
+    if let Ok(hostname) = env::var("HOSTNAME") {
+        return hostname;
+    }
+    match Command::new("hostname").output() {
+        Ok(output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).trim().to_string()
+        }
+        _ => "unknown".to_string(),
+    }
+}
+
+fn reject<S>(stream: &mut S, reason: String) -> String
+where
+    S: Read + Write,
+{
+    let _ = send_message(
+        stream,
+        create_message(0, Function::Close, reason.clone().into_bytes()),
+    );
+    reason
+}
+
+fn read_hello<S>(stream: &mut S) -> Result<Message, String>
+where
+    S: Read + Write,
+{
+    match read_message(stream) {
+        Ok(Some(message)) => Ok(message),
+        Ok(None) => Err("ERROR: Peer closed the connection during the HELLO".to_string()),
+        Err(err) => Err(format!("ERROR: Unable to read the HELLO\n{err}")),
+    }
+}
+
+/// The value of `key` in a body of `key=value` lines.
+fn body_value(body: &[u8], key: &str) -> Option<String> {
+    String::from_utf8_lossy(body).lines().find_map(|line| {
+        let (name, value) = line.split_once('=')?;
+        (name == key).then(|| value.to_string())
+    })
+}
+
+/// Challenges the Container to prove the knowledge of `token`, a wrong answer ends the session.
+fn authenticate<S>(stream: &mut S, token: &str) -> Result<(), String>
+where
+    S: Read + Write,
+{
+    let challenge = generate_token().map_err(|err| reject(stream, err))?;
+    send_message(
+        stream,
+        create_message(
+            0,
+            Function::Auth,
+            format!("challenge={challenge}\n").into_bytes(),
+        ),
+    )
+    .map_err(|err| format!("ERROR: Unable to send the challenge\n{err}"))?;
+    let message = read_hello(stream)?;
+    let response = match message.header.function {
+        Function::Auth => body_value(&message.body, "response"),
+        _ => None,
+    };
+    match response {
+        Some(response) if verify(token, &challenge, &response) => Ok(()),
+        _ => Err(reject(
+            stream,
+            "ERROR: Authentication failed, the Container does not know the token of the Host"
+                .to_string(),
+        )),
+    }
+}
+
+/// Host side of the handshake, returns the agreed session parameters of the Container.
+///
+/// A peer not starting with a compatible HELLO is refused with a `Close` message carrying the reason.
+pub fn accept_hello<S>(stream: S, local: &Hello) -> Result<Hello, String>
+where
+    S: Read + Write,
+{
+    accept_hello_with_token(stream, local, None)
+}
+
+/// Like `accept_hello`, but the Container has to prove the knowledge of `token` before the
+/// HELLO is answered.
+///
+/// The Host sends an **AUTH** with a random `challenge`, which the Container answers with an
+/// **AUTH** carrying the HMAC-SHA256 of the challenge as `response`, see `respond`.
+///
+/// A Container, which never answers, blocks until the read timeout of `stream` expires.
+pub fn accept_hello_with_token<S>(
+    mut stream: S,
+    local: &Hello,
+    token: Option<&str>,
+) -> Result<Hello, String>
+where
+    S: Read + Write,
+{
+    let message = read_hello(&mut stream)?;
+    if message.header.function != Function::Hello {
+        return Err(reject(
+            &mut stream,
+            "ERROR: Peer did not start with a HELLO, autoForward 0.0.5 and older is not supported"
+                .to_string(),
+        ));
+    }
+    let remote = Hello::decode(&message.body).map_err(|err| reject(&mut stream, err))?;
+    let agreed = local
+        .negotiate(&remote)
+        .map_err(|err| reject(&mut stream, err))?;
+    if let Some(token) = token {
+        authenticate(&mut stream, token)?;
+    }
+    send_message(
+        &mut stream,
+        create_message(0, Function::Hello, agreed.encode()),
+    )
+    .map_err(|err| format!("ERROR: Unable to answer the HELLO\n{err}"))?;
+    Ok(Hello {
+        protocol: agreed.protocol,
+        capabilities: agreed.capabilities,
+        ..remote
+    })
+}
+
+/// Container side of the handshake, returns the agreed session parameters of the Host.
+pub fn send_hello<S>(stream: S, local: &Hello) -> Result<Hello, String>
+where
+    S: Read + Write,
+{
+    send_hello_with_token(stream, local, None)
+}
+
+/// Like `send_hello`, but answers the challenge of a Host requiring a token with `token`.
+pub fn send_hello_with_token<S>(
+    mut stream: S,
+    local: &Hello,
+    token: Option<&str>,
+) -> Result<Hello, String>
+where
+    S: Read + Write,
+{
+    send_message(
+        &mut stream,
+        create_message(0, Function::Hello, local.encode()),
+    )
+    .map_err(|err| format!("ERROR: Unable to send the HELLO\n{err}"))?;
+    let mut message = read_hello(&mut stream)?;
+    if message.header.function == Function::Auth {
+        let challenge = body_value(&message.body, "challenge").unwrap_or_default();
+        let token = token.ok_or_else(|| {
+            reject(
+                &mut stream,
+                "ERROR: The Host requires a token, set AUTO_FORWARD_TOKEN to the output of `host token`"
+                    .to_string(),
+            )
+        })?;
+        let response = respond(token, &challenge);
+        send_message(
+            &mut stream,
+            create_message(
+                0,
+                Function::Auth,
+                format!("response={response}\n").into_bytes(),
+            ),
+        )
+        .map_err(|err| format!("ERROR: Unable to answer the challenge\n{err}"))?;
+        message = read_hello(&mut stream)?;
+    }
+    match message.header.function {
+        Function::Hello => {}
+        Function::Close => {
+            return Err(format!(
+                "Host refused the Session: {}",
+                String::from_utf8_lossy(&message.body)
+            ))
+        }
+        _ => {
+            return Err(reject(
+                &mut stream,
+                "ERROR: Host did not answer the HELLO".to_string(),
+            ))
+        }
+    }
+    let remote = Hello::decode(&message.body).map_err(|err| reject(&mut stream, err))?;
+    if remote.protocol > local.protocol || remote.protocol < MIN_PROTOCOL_VERSION {
+        return Err(reject(
+            &mut stream,
+            format!(
+                "ERROR: Host selected the unsupported protocol version {}",
+                remote.protocol
+            ),
+        ));
+    }
+    Ok(remote)
+}
+
+#[cfg(test)]
+mod test_hello {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    #[test]
+    fn ensure_inverse() {
+        let hello = Hello::new("devbox".to_string(), Some("rust".to_string()));
+        assert_eq!(hello, Hello::decode(&hello.encode()).unwrap());
+    }
+
+    #[test]
+    fn ignore_unknown_keys() {
+        let hello = Hello::decode(b"protocol=7\nfancy=yes\nhostname=a\n").unwrap();
+        assert_eq!(7, hello.protocol);
+        assert_eq!("a", hello.hostname);
+        assert!(Hello::decode(b"hostname=a\n").is_err());
+    }
+
+    #[test]
+    fn identity() {
+        assert_eq!("devbox", Hello::new("devbox".to_string(), None).identity());
+        let hello = Hello::new("devbox".to_string(), Some("rust".to_string()));
+        assert_eq!("devbox/rust", hello.identity());
+    }
+
+    #[test]
+    fn negotiate_downgrades() {
+        let local = Hello::new("host".to_string(), None);
+        let mut remote = Hello::new("container".to_string(), None);
+        remote.protocol = PROTOCOL_VERSION + 1;
+        remote.capabilities = vec!["tcp".to_string(), "future".to_string()];
+        let agreed = local.negotiate(&remote).unwrap();
+        assert_eq!(PROTOCOL_VERSION, agreed.protocol);
+        assert_eq!(vec!["tcp".to_string()], agreed.capabilities);
+
+        remote.protocol = MIN_PROTOCOL_VERSION - 1;
+        assert!(local.negotiate(&remote).is_err());
+    }
+
+    #[test]
+    fn handshake() {
+        let host = TcpListener::bind("127.0.0.1:0").unwrap();
+        let container = TcpStream::connect(host.local_addr().unwrap()).unwrap();
+        let (stream, _) = host.accept().unwrap();
+        let accepted =
+            thread::spawn(move || accept_hello(&stream, &Hello::new("host".to_string(), None)));
+        let local = Hello::new("container".to_string(), Some("rust".to_string()));
+        let host_hello = send_hello(&container, &local).unwrap();
+        let container_hello = accepted.join().unwrap().unwrap();
+        assert_eq!("host", host_hello.hostname);
+        assert_eq!("container", container_hello.hostname);
+        assert_eq!(Some("rust".to_string()), container_hello.devcontainer);
+    }
+
+    #[test]
+    fn handshake_with_token() {
+        let handshake = |host_token: &'static str, container_token: Option<&'static str>| {
+            let host = TcpListener::bind("127.0.0.1:0").unwrap();
+            let container = TcpStream::connect(host.local_addr().unwrap()).unwrap();
+            let (stream, _) = host.accept().unwrap();
+            let accepted = thread::spawn(move || {
+                let local = Hello::new("host".to_string(), None);
+                accept_hello_with_token(&stream, &local, Some(host_token))
+            });
+            let local = Hello::new("container".to_string(), None);
+            let sent = send_hello_with_token(&container, &local, container_token);
+            (sent, accepted.join().unwrap())
+        };
+        let (sent, accepted) = handshake("secret", Some("secret"));
+        assert_eq!("host", sent.unwrap().hostname);
+        assert_eq!("container", accepted.unwrap().hostname);
+
+        let (sent, accepted) = handshake("secret", Some("guess"));
+        assert!(sent.unwrap_err().contains("Authentication failed"));
+        assert!(accepted.is_err());
+
+        let (sent, accepted) = handshake("secret", None);
+        assert!(sent.unwrap_err().contains("requires a token"));
+        assert!(accepted.is_err());
+    }
+
+    #[test]
+    fn refuse_legacy_peer() {
+        let host = TcpListener::bind("127.0.0.1:0").unwrap();
+        let container = TcpStream::connect(host.local_addr().unwrap()).unwrap();
+        let (stream, _) = host.accept().unwrap();
+        let mut writer = &container;
+        send_message(
+            &mut writer,
+            create_message(3000, Function::CreateTcp, Vec::new()),
+        )
+        .unwrap();
+        assert!(accept_hello(&stream, &Hello::new("host".to_string(), None)).is_err());
+        let refusal = read_message(&container).unwrap().unwrap();
+        assert_eq!(Function::Close, refusal.header.function);
+    }
+}
@@ -1,42 +1,168 @@
+use serde::{Deserialize, Serialize};
 use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::fmt;
 use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
+use std::io::ErrorKind;
+use std::net::{
+    IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket,
+};
 use std::str;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::RwLock;
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[macro_use]
+mod log;
+mod auth;
+mod config;
+#[cfg(unix)]
+mod control;
+mod detector;
+mod devcontainer;
+mod error;
+mod handshake;
+#[cfg(target_os = "linux")]
+mod netlink;
+mod port_policy;
+mod proc_net;
+mod request;
+mod rules;
+mod secure;
+#[cfg(test)]
+mod test_util;
+mod transport;
+mod tunnel;
+
+pub use auth::*;
+pub use config::*;
+#[cfg(unix)]
+pub use control::*;
+pub use detector::*;
+pub use devcontainer::*;
+pub use error::*;
+pub use handshake::*;
+pub use log::*;
+#[cfg(target_os = "linux")]
+pub use netlink::*;
+pub use port_policy::*;
+pub use proc_net::*;
+pub use request::*;
+pub use rules::*;
+pub use secure::*;
+pub use transport::*;
+pub use tunnel::*;
+
+/// Size of the frame header in bytes.
+pub const HEADER_SIZE: usize = 12;
+
+/// Largest body a frame may announce, anything larger is treated as a corrupted stream.
+pub const MAX_MESSAGE_SIZE: u32 = 1024 * 1024;
+
+/// Maximal number of bytes read from a forwarded socket into a single frame.
+const STREAM_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Large enough to hold any UDP datagram, so message boundaries are never split.
+pub const UDP_BUFFER_SIZE: usize = 64 * 1024;
+
+/// A UDP session without traffic in either direction for this long is dropped.
+pub const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often UDP sockets wake up to look for idle sessions.
+pub const UDP_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Set in the ids of the streams opened by the Container, so they never collide with the ids
+/// assigned by the Multiplexer.
+pub const REVERSE_STREAM: u32 = 1 << 31;
+
+/// Next id of a stream opened by the Container, shared by all its reverse listeners, as the
+/// streams of all ports are routed by their id alone.
+static NEXT_REVERSE_STREAM: AtomicU32 = AtomicU32::new(0);
+
+/// Time the Host waits for a service of a reverse forwarded port to accept the connection.
+const REVERSE_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Function {
+    Hello,
+    /// Challenge of the Host and the answer of the Container, see `accept_hello_with_token`.
+    Auth,
+    Close,
+    CloseTcp,
+    CloseUdp,
     CreateTcp,
     CreateUdp,
+    CreatedTcp,
+    CreatedUdp,
     Tcp,
+    TcpOpen,
+    TcpShutdown,
+    TcpReset,
+    /// Opens a stream to a port of the Host, sent by the Container.
+    ConnectTcp,
     Udp,
 }
 
 impl Function {
     fn encode(&self) -> u8 {
         match self {
+            Function::Hello => 0b1000_0001,
+            Function::Auth => 0b0100_0001,
+            Function::Close => 0b0000_0001,
+            Function::CloseTcp => 0b0000_1101,
+            Function::CloseUdp => 0b0000_1011,
             Function::CreateTcp => 0b0000_1100,
             Function::CreateUdp => 0b0000_1010,
+            Function::CreatedTcp => 0b0001_1100,
+            Function::CreatedUdp => 0b0001_1010,
             Function::Tcp => 0b0000_0100,
+            Function::TcpOpen => 0b0010_0100,
+            Function::TcpShutdown => 0b0100_0100,
+            Function::TcpReset => 0b1000_0100,
+            Function::ConnectTcp => 0b0011_0100,
             Function::Udp => 0b0000_0010,
         }
     }
 
-    fn decode(byte: u8) -> Function {
-        match byte {
+    fn decode(byte: u8) -> Result<Function, ProtocolError> {
+        Ok(match byte {
+            0b1000_0001 => Function::Hello,
+            0b0100_0001 => Function::Auth,
+            0b0000_0001 => Function::Close,
+            0b0000_1101 => Function::CloseTcp,
+            0b0000_1011 => Function::CloseUdp,
             0b0000_1100 => Function::CreateTcp,
             0b0000_1010 => Function::CreateUdp,
+            0b0001_1100 => Function::CreatedTcp,
+            0b0001_1010 => Function::CreatedUdp,
             0b0000_0100 => Function::Tcp,
+            0b0010_0100 => Function::TcpOpen,
+            0b0100_0100 => Function::TcpShutdown,
+            0b1000_0100 => Function::TcpReset,
+            0b0011_0100 => Function::ConnectTcp,
             0b0000_0010 => Function::Udp,
-            _ => {
-                println!("{byte}");
-                todo!()
+            _ => return Err(ProtocolError::UnknownFunction(byte)),
+        })
+    }
+
+    /// The transport protocol a function belongs to, `None` for functions of the whole session.
+    pub fn protocol(&self) -> Option<Protocol> {
+        match self {
+            Function::Hello | Function::Auth | Function::Close => None,
+            Function::CloseTcp
+            | Function::CreateTcp
+            | Function::CreatedTcp
+            | Function::Tcp
+            | Function::TcpOpen
+            | Function::TcpShutdown
+            | Function::TcpReset
+            | Function::ConnectTcp => Some(Protocol::TCP),
+            Function::CloseUdp | Function::CreateUdp | Function::CreatedUdp | Function::Udp => {
+                Some(Protocol::UDP)
             }
         }
     }
@@ -50,21 +176,70 @@ mod test_function {
     fn ensure_inverse() {
         assert_eq!(
             Function::CreateTcp,
-            Function::decode(Function::encode(&Function::CreateTcp))
+            Function::decode(Function::encode(&Function::CreateTcp)).unwrap()
         );
         assert_eq!(
             Function::CreateUdp,
-            Function::decode(Function::encode(&Function::CreateUdp))
+            Function::decode(Function::encode(&Function::CreateUdp)).unwrap()
         );
         assert_eq!(
             Function::Tcp,
-            Function::decode(Function::encode(&Function::Tcp))
+            Function::decode(Function::encode(&Function::Tcp)).unwrap()
         );
         assert_eq!(
             Function::Udp,
-            Function::decode(Function::encode(&Function::Udp))
+            Function::decode(Function::encode(&Function::Udp)).unwrap()
         );
     }
+
+    #[test]
+    fn decode_unknown() {
+        assert!(matches!(
+            Function::decode(0b1111_1111),
+            Err(ProtocolError::UnknownFunction(0b1111_1111))
+        ));
+    }
+
+    #[test]
+    fn ensure_inverse_close_functions() {
+        for function in [
+            Function::Hello,
+            Function::Auth,
+            Function::Close,
+            Function::CloseTcp,
+            Function::CloseUdp,
+        ] {
+            assert_eq!(
+                function,
+                Function::decode(Function::encode(&function)).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn ensure_inverse_created_functions() {
+        for function in [Function::CreatedTcp, Function::CreatedUdp] {
+            assert_eq!(
+                function,
+                Function::decode(Function::encode(&function)).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn ensure_inverse_stream_functions() {
+        for function in [
+            Function::TcpOpen,
+            Function::TcpShutdown,
+            Function::TcpReset,
+            Function::ConnectTcp,
+        ] {
+            assert_eq!(
+                function,
+                Function::decode(Function::encode(&function)).unwrap()
+            );
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -72,11 +247,13 @@ pub struct Header {
     pub message_size: u32,
     pub function: Function,
     pub port: u16,
+    /// Identifies a single forwarded connection, `0` for messages addressing the whole port.
+    pub stream: u32,
 }
 
 impl Header {
-    pub fn encode(&self) -> [u8; 8] {
-        let mut result = [0; 8];
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut result = [0; HEADER_SIZE];
         result[0] = self.message_size.to_be_bytes()[0];
         result[1] = self.message_size.to_be_bytes()[1];
         result[2] = self.message_size.to_be_bytes()[2];
@@ -84,15 +261,17 @@ impl Header {
         result[4] = self.function.encode();
         result[5] = self.port.to_be_bytes()[0];
         result[6] = self.port.to_be_bytes()[1];
+        result[8..12].copy_from_slice(&self.stream.to_be_bytes());
         result
     }
 
-    pub fn decode(buffer: &[u8; 8]) -> Header {
-        Header {
+    pub fn decode(buffer: &[u8; HEADER_SIZE]) -> Result<Header, ProtocolError> {
+        Ok(Header {
             message_size: u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]),
-            function: Function::decode(buffer[4]),
+            function: Function::decode(buffer[4])?,
             port: u16::from_be_bytes([buffer[5], buffer[6]]),
-        }
+            stream: u32::from_be_bytes([buffer[8], buffer[9], buffer[10], buffer[11]]),
+        })
     }
 }
 
@@ -102,21 +281,35 @@ mod test_header {
 
     #[test]
     fn decode_header_correctly() {
-        let input_header: [u8; 8] = [0, 0, 0, 200, 0b1100, 0x0B, 0xB8, 0];
+        let input_header: [u8; HEADER_SIZE] = [0, 0, 0, 200, 0b1100, 0x0B, 0xB8, 0, 0, 0, 0, 0];
         let expected = Header {
             message_size: 200,
             function: Function::CreateTcp,
             port: 3000,
+            stream: 0,
         };
-        let result = Header::decode(&input_header);
+        let result = Header::decode(&input_header).unwrap();
         assert_eq!(expected.message_size, result.message_size);
         assert_eq!(expected.function, result.function);
         assert_eq!(expected.port, result.port);
     }
+
+    #[test]
+    fn ensure_inverse_with_stream() {
+        let header = Header {
+            message_size: 1024,
+            function: Function::Tcp,
+            port: 8080,
+            stream: 0xDEAD_BEEF,
+        };
+        assert_eq!(header, Header::decode(&header.encode()).unwrap());
+    }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Protocol {
+    #[default]
     TCP,
     UDP,
 }
@@ -131,6 +324,17 @@ impl Protocol {
     }
 }
 
+/// A socket inside the Container, which is ready to accept connections or Datagrams.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ListenPort {
+    pub port: u16,
+    /// Address the service is reachable at, `localhost` if it listens on every interface.
+    pub ip: String,
+    pub protocol: Protocol,
+    /// Name of the process owning the socket.
+    pub app: String,
+}
+
 #[cfg(test)]
 mod test_protocol {
     use super::*;
@@ -171,143 +375,689 @@ impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "Header:\n\tMessage Size: {}\n\tFunction: {:#?}\n\tPort: {}\nMessage:\n{}",
+            "Header:\n\tMessage Size: {}\n\tFunction: {:#?}\n\tPort: {}\n\tStream: {}\nMessage:\n{}",
             self.header.message_size,
             self.header.function,
             self.header.port,
+            self.header.stream,
             str::from_utf8(&self.body)
                 .unwrap_or(format!("Unable to decode {:#?}", self.body).as_str()),
         )
     }
 }
 
-pub struct Multiplexer {
-    stream: RefCell<TcpStream>,
-    connection: Arc<RwLock<HashMap<u16, Arc<Connection>>>>,
+/// The Host side of a Session, running over any `Transport`, a `Tunnel` for the Host Program.
+pub struct Multiplexer<T: Transport = Tunnel> {
+    stream: RefCell<T>,
+    connection: Connections,
+    streams: Arc<RwLock<HashMap<u32, Sender<Message>>>>,
     receiver: Arc<Mutex<Receiver<Message>>>,
-    _sender: Sender<Message>,
+    sender: Sender<Message>,
     default: Sender<Message>,
-    receiver_connection: Arc<Mutex<Receiver<Connection>>>,
+    hello: Hello,
+    peer: Arc<RwLock<Option<Hello>>>,
+    host_ports: HostPorts,
+    session: Session,
+}
+
+/// Forwarded ports of a Multiplexer, identified by the container port and its protocol.
+type Connections = Arc<RwLock<HashMap<(u16, Protocol), Arc<Connection>>>>;
+
+/// Channels of the watchers of a Multiplexer, see `SessionHandle::watch`.
+type Watchers = Arc<Mutex<Vec<Sender<PortChange>>>>;
+
+fn notify(watchers: &Watchers, change: PortChange) {
+    // Watchers, which are gone, are dropped.
+    watchers
+        .lock()
+        .unwrap()
+        .retain(|watcher| watcher.send(change.clone()).is_ok());
 }
 
 struct Connection {
     port: u16,
-    _host_port: u16,
-    _protocol: Protocol,
-    _app: String,
+    host_port: u16,
+    protocol: Protocol,
+    app: String,
+    label: Option<String>,
     connection: Mutex<Sender<Message>>,
+    /// Address the host listener is bound to.
+    address: SocketAddr,
+    closed: Arc<AtomicBool>,
+    listener: Mutex<Option<JoinHandle<()>>>,
+    watchers: Watchers,
+    stats: Arc<PortStats>,
 }
 
-impl Multiplexer {
-    pub fn new(stream: TcpStream) -> Multiplexer {
-        if !stream
-            .nodelay()
-            .expect("Unable to read delay Mode of Socket")
-        {
-            stream.set_nodelay(true).expect("Unable to enable nodelay");
+/// Traffic of a forwarded port.
+#[derive(Default)]
+struct PortStats {
+    /// Open TCP connections or UDP sessions.
+    active: AtomicUsize,
+    /// Payload forwarded in both directions.
+    bytes: AtomicU64,
+}
+
+/// Adds the payload of a stream frame to the traffic of its port.
+fn count_bytes(connections: &Connections, message: &Message) {
+    // Reverse streams belong to a port of the Host, not to a forwarded port.
+    if message.header.stream & REVERSE_STREAM != 0 {
+        return;
+    }
+    let protocol = match message.header.function {
+        Function::Tcp => Protocol::TCP,
+        Function::Udp => Protocol::UDP,
+        _ => return,
+    };
+    if let Some(connection) = connections
+        .read()
+        .unwrap()
+        .get(&(message.header.port, protocol))
+    {
+        let size = message.body.len() as u64;
+        connection.stats.bytes.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+impl Connection {
+    fn forwarded(&self) -> ForwardedPort {
+        ForwardedPort {
+            port: self.port,
+            protocol: self.protocol.clone(),
+            host_port: self.host_port,
+            address: self.address,
+            app: self.app.clone(),
+            label: self.label.clone(),
+            connections: self.stats.active.load(Ordering::Relaxed),
+            bytes: self.stats.bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Stops the host listener and waits until its port is free again.
+    ///
+    /// Streams accepted before stay open until one of the sides finishes them.
+    fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let mut address = self.address;
+        if address.ip().is_unspecified() {
+            address.set_ip(match address {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            });
+        }
+        // The listener is blocked in accept or recv, so it has to be woken up to notice the close.
+        let woken = match self.protocol {
+            Protocol::TCP => TcpStream::connect(address).map(|_| ()),
+            Protocol::UDP => UdpSocket::bind(SocketAddr::new(address.ip(), 0))
+                .and_then(|socket| socket.send_to(&[], address))
+                .map(|_| ()),
+        };
+        match woken {
+            Ok(()) => {
+                if let Some(listener) = self.listener.lock().unwrap().take() {
+                    let _ = listener.join();
+                }
+            }
+            Err(err) => error!("ERROR: Unable to stop listener on {address}\n{err}"),
+        }
+        info!("Closed Port {:?} {}", self.protocol, self.port);
+        notify(
+            &self.watchers,
+            PortChange::Closed {
+                port: self.port,
+                protocol: self.protocol.clone(),
+            },
+        );
+    }
+}
+
+/// A port of the Container, which is forwarded to the Host.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct ForwardedPort {
+    pub port: u16,
+    pub protocol: Protocol,
+    pub host_port: u16,
+    /// Address of the listener on the Host.
+    pub address: SocketAddr,
+    pub app: String,
+    pub label: Option<String>,
+    /// Open TCP connections or UDP sessions.
+    #[serde(default)]
+    pub connections: usize,
+    /// Payload forwarded in both directions.
+    #[serde(default)]
+    pub bytes: u64,
+}
+
+/// A change of the forwarded ports of a Multiplexer.
+#[derive(Debug, PartialEq, Clone)]
+pub enum PortChange {
+    Forwarded(ForwardedPort),
+    Refused {
+        port: u16,
+        protocol: Protocol,
+        reason: String,
+    },
+    Closed {
+        port: u16,
+        protocol: Protocol,
+    },
+}
+
+/// Host port of a forwarded port, identified by the Container, the container port and its protocol.
+type AssignedPorts = HashMap<(String, u16, Protocol), u16>;
+
+/// Host ports assigned to the ports of every Container, kept beyond the end of its sessions.
+#[derive(Clone)]
+pub struct HostPorts {
+    /// Address the listeners of the forwarded ports are bound to.
+    bind: String,
+    assigned: Arc<Mutex<AssignedPorts>>,
+    policy: PortPolicy,
+    /// Forwarding settings of the workspace given to the Host, applied to every Container.
+    devcontainer: Option<Arc<DevContainer>>,
+    /// Ports of the Host the Containers may connect to, see `HostPorts::with_reverse`.
+    reverse: Arc<Vec<u16>>,
+}
+
+impl Default for HostPorts {
+    fn default() -> HostPorts {
+        HostPorts::with_bind("localhost")
+    }
+}
+
+impl HostPorts {
+    pub fn new() -> HostPorts {
+        HostPorts::default()
+    }
+
+    /// Binds the listeners of the forwarded ports to `bind` instead of `localhost`.
+    pub fn with_bind(bind: &str) -> HostPorts {
+        HostPorts {
+            bind: bind.to_string(),
+            assigned: Arc::new(Mutex::new(HashMap::new())),
+            policy: PortPolicy::default(),
+            devcontainer: None,
+            reverse: Arc::new(Vec::new()),
         }
+    }
+
+    /// Picks the host ports with `policy`, instead of taking the next free port.
+    pub fn with_policy(mut self, policy: PortPolicy) -> HostPorts {
+        self.policy = policy;
+        self
+    }
+
+    /// Forwards the `forwardPorts` of `devcontainer` for every Container and applies its port
+    /// attributes to the ports requested by the Containers.
+    pub fn with_devcontainer(mut self, devcontainer: DevContainer) -> HostPorts {
+        self.devcontainer = Some(Arc::new(devcontainer));
+        self
+    }
+
+    /// Lets the Containers connect to `ports` on `localhost` of the Host, all other ports are reset.
+    pub fn with_reverse(mut self, ports: Vec<u16>) -> HostPorts {
+        self.reverse = Arc::new(ports);
+        self
+    }
+
+    /// The host port the Container got for `port` before, otherwise the port itself.
+    fn preferred(&self, container: &str, port: u16, protocol: &Protocol) -> u16 {
+        let key = (container.to_string(), port, protocol.clone());
+        match self.assigned.lock().unwrap().get(&key) {
+            Some(host_port) => *host_port,
+            None => port,
+        }
+    }
+
+    fn assign(&self, container: String, port: u16, protocol: Protocol, host_port: u16) {
+        self.assigned
+            .lock()
+            .unwrap()
+            .insert((container, port, protocol), host_port);
+    }
+}
+
+/// State of a Multiplexer shared with the threads setting up its listeners.
+#[derive(Clone)]
+struct Session {
+    sender: Sender<Message>,
+    connections: Connections,
+    streams: Arc<RwLock<HashMap<u32, Sender<Message>>>>,
+    next_stream: Arc<AtomicU32>,
+    peer: Arc<RwLock<Option<Hello>>>,
+    host_ports: HostPorts,
+    /// Requests of the Container are refused while auto forwarding is paused.
+    paused: Arc<AtomicBool>,
+    watchers: Watchers,
+    /// Held while a port is set up, so a port is never forwarded twice.
+    setup: Arc<Mutex<()>>,
+}
+
+impl Session {
+    /// Identity of the Container, used to find the host ports of its previous sessions.
+    fn container(&self) -> String {
+        match &*self.peer.read().unwrap() {
+            Some(peer) => peer.identity(),
+            None => String::new(),
+        }
+    }
+
+    /// Answers a request for `port`, if the Container understands the answer.
+    fn reply(&self, port: u16, protocol: &Protocol, reply: PortReply) {
+        let supported = match &*self.peer.read().unwrap() {
+            Some(peer) => peer.supports("created"),
+            None => false,
+        };
+        if supported {
+            let function = match protocol {
+                Protocol::TCP => Function::CreatedTcp,
+                Protocol::UDP => Function::CreatedUdp,
+            };
+            let _ = self
+                .sender
+                .send(create_message(port, function, reply.encode()));
+        }
+    }
+
+    fn refuse(&self, port: u16, protocol: &Protocol, reason: String) {
+        self.reply(port, protocol, PortReply::Refused(reason.clone()));
+        let protocol = protocol.clone();
+        notify(
+            &self.watchers,
+            PortChange::Refused {
+                port,
+                protocol,
+                reason,
+            },
+        );
+    }
+
+    /// Forwards `port` of the Container, unless it is forwarded already.
+    fn forward(
+        &self,
+        port: u16,
+        protocol: Protocol,
+        request: PortRequest,
+    ) -> Result<SocketAddr, String> {
+        let _setup = self.setup.lock().unwrap();
+        let forwarded = self
+            .connections
+            .read()
+            .unwrap()
+            .get(&(port, protocol.clone()))
+            .map(|connection| connection.address);
+        // The Host forwards the ports of its devcontainer.json before the Container asks for them.
+        if let Some(address) = forwarded {
+            info!("Port {port} is already forwarded");
+            self.reply(port, &protocol, PortReply::Forwarded(address));
+            return Ok(address);
+        }
+        match protocol {
+            Protocol::TCP => setup_tcp_listener(port, request, self),
+            Protocol::UDP => setup_udp_listener(port, request, self),
+        }
+    }
+}
+
+/// Handle to a running Multiplexer, used to inspect and change its forwarded ports from other
+/// threads.
+#[derive(Clone)]
+pub struct SessionHandle {
+    session: Session,
+}
+
+impl SessionHandle {
+    /// The Container of this session, known after the HELLO was accepted.
+    pub fn peer(&self) -> Option<Hello> {
+        self.session.peer.read().unwrap().clone()
+    }
+
+    /// Sender for messages to the Container, see `Multiplexer::sender`.
+    pub fn sender(&self) -> Sender<Message> {
+        self.session.sender.clone()
+    }
+
+    /// The forwarded ports, ordered by port and protocol.
+    pub fn ports(&self) -> Vec<ForwardedPort> {
+        let mut ports = self
+            .session
+            .connections
+            .read()
+            .unwrap()
+            .values()
+            .map(|connection| connection.forwarded())
+            .collect::<Vec<ForwardedPort>>();
+        ports.sort_by_key(|port| (port.port, port.protocol == Protocol::UDP));
+        ports
+    }
+
+    /// Forwards `port` of the Container, even if auto forwarding is paused or the
+    /// devcontainer.json of the Host ignores it.
+    pub fn forward(
+        &self,
+        port: u16,
+        protocol: Protocol,
+        mut request: PortRequest,
+    ) -> Result<SocketAddr, String> {
+        if let Some(devcontainer) = &self.session.host_ports.devcontainer {
+            let listen_port = ListenPort {
+                port,
+                ip: "localhost".to_string(),
+                protocol: protocol.clone(),
+                app: request.app.clone(),
+            };
+            request = request.merge(devcontainer.attributes(&listen_port));
+        }
+        self.session.forward(port, protocol, request)
+    }
+
+    /// Stops forwarding `port`, the Container may request it again.
+    pub fn unforward(&self, port: u16, protocol: Protocol) -> Result<(), String> {
+        let key = (port, protocol);
+        let connection = self.session.connections.write().unwrap().remove(&key);
+        match connection {
+            Some(connection) => {
+                connection.close();
+                Ok(())
+            }
+            None => Err(format!("ERROR: Port {:?} {port} is not forwarded", key.1)),
+        }
+    }
+
+    /// Refuses the ports requested by the Container, until auto forwarding is resumed.
+    pub fn pause(&self, paused: bool) {
+        self.session.paused.store(paused, Ordering::SeqCst);
+    }
+
+    pub fn paused(&self) -> bool {
+        self.session.paused.load(Ordering::SeqCst)
+    }
+
+    /// Receives every change of the forwarded ports from now on.
+    pub fn watch(&self) -> Receiver<PortChange> {
+        let (sender, receiver) = channel();
+        self.session.watchers.lock().unwrap().push(sender);
+        receiver
+    }
+}
+
+fn close_connections(connections: &Connections) {
+    let closed = connections
+        .write()
+        .unwrap()
+        .drain()
+        .map(|(_, connection)| connection)
+        .collect::<Vec<Arc<Connection>>>();
+    for connection in closed {
+        connection.close();
+    }
+}
+
+impl<T: Transport> Multiplexer<T> {
+    pub fn new(stream: T) -> Multiplexer<T> {
+        Multiplexer::with_host_ports(stream, HostPorts::new())
+    }
+
+    /// Creates a Multiplexer, which remembers its host ports in `host_ports`.
+    ///
+    /// Sharing `host_ports` between the sessions gives a reconnecting Container the host ports of
+    /// its previous session again.
+    pub fn with_host_ports(stream: T, host_ports: HostPorts) -> Multiplexer<T> {
+        stream.set_nodelay(true).expect("Unable to enable nodelay");
         // stream
         //     .set_nonblocking(true)
         //     .expect("Unable to enable non Blocking");
         let (sender, receiver) = channel();
         let (default_sender, default_receiver) = channel();
-        let (connection_sender, connection_receiver) = channel();
-        let multi = Multiplexer {
+        let connection: Connections = Arc::new(RwLock::new(HashMap::new()));
+        let streams = Arc::new(RwLock::new(HashMap::new()));
+        let peer = Arc::new(RwLock::new(None));
+        let session = Session {
+            sender: sender.clone(),
+            connections: connection.clone(),
+            streams: streams.clone(),
+            next_stream: Arc::new(AtomicU32::new(1)),
+            peer: peer.clone(),
+            host_ports: host_ports.clone(),
+            paused: Arc::new(AtomicBool::new(false)),
+            watchers: Arc::new(Mutex::new(Vec::new())),
+            setup: Arc::new(Mutex::new(())),
+        };
+        let unknown_session = session.clone();
+        thread::spawn(move || handle_unknown_port(default_receiver, unknown_session));
+        Multiplexer {
             stream: RefCell::new(stream),
-            connection: Arc::new(RwLock::new(HashMap::new())),
+            connection,
+            streams,
             receiver: Arc::new(Mutex::new(receiver)),
-            _sender: sender.clone(),
+            sender,
             default: default_sender,
-            receiver_connection: Arc::new(Mutex::new(connection_receiver)),
-        };
-        thread::spawn(move || {
-            handle_unknown_port(default_receiver, sender.clone(), connection_sender)
-        });
-        multi
+            hello: Hello::new(local_hostname(), None),
+            peer,
+            host_ports,
+            session,
+        }
+    }
+
+    /// Starts the session with `peer`, whose HELLO was already accepted, e.g. with
+    /// `accept_hello_with_token`.
+    pub fn with_peer(self, peer: Hello) -> Multiplexer<T> {
+        *self.peer.write().unwrap() = Some(peer);
+        self
     }
 
     pub fn run(&self) {
-        let read_stream = self.stream.borrow().try_clone().unwrap();
+        if self.peer().is_none() {
+            match accept_hello(&mut *self.stream.borrow_mut(), &self.hello) {
+                Ok(peer) => *self.peer.write().unwrap() = Some(peer),
+                Err(err) => {
+                    error!("{err}");
+                    return;
+                }
+            }
+        }
+        if let Some(peer) = self.peer() {
+            info!("Session with {peer}");
+        }
+        if let Some(devcontainer) = &self.host_ports.devcontainer {
+            // Only ports of the Container itself are reachable without its devcontainer.json.
+            for port in devcontainer
+                .forward_ports
+                .iter()
+                .filter(|port| port.ip == "localhost")
+            {
+                let request = PortRequest::new(port, devcontainer.attributes(port));
+                let _ = self.default.send(create_message(
+                    port.port,
+                    Function::CreateTcp,
+                    request.encode(),
+                ));
+            }
+        }
+        let mut read_stream = self.stream.borrow().try_clone().unwrap();
         let mut write_stream = self.stream.borrow().try_clone().unwrap();
         let connections = self.connection.clone();
+        let streams = self.streams.clone();
         let default = self.default.clone();
-        let read_thread = thread::spawn(move || loop {
-            match read_message(&read_stream) {
-                Ok(message) => match message {
-                    Some(message) => handle_socket_message(connections.clone(), &default, message),
-                    None => {
-                        println!("Container closed Socket!");
+        let sender = self.sender.clone();
+        let session = self.session.clone();
+        let read_thread = thread::spawn(move || {
+            loop {
+                match read_message(&mut read_stream) {
+                    Ok(message) => match message {
+                        Some(message) if message.header.function == Function::Close => {
+                            info!("Container closed the Session");
+                            break;
+                        }
+                        Some(message) if message.header.function == Function::ConnectTcp => {
+                            connect_reverse(message, &session)
+                        }
+                        Some(message) => {
+                            handle_socket_message(connections.clone(), &streams, &default, message)
+                        }
+                        None => {
+                            info!("Container closed the Socket");
+                            break;
+                        }
+                    },
+                    Err(err) if err.is_recoverable() => {
+                        error!("ERROR: Ignoring Message of the Container\n{err}")
+                    }
+                    Err(err @ ProtocolError::OversizedFrame { .. }) => {
+                        error!("ERROR: Closing the Session, the Stream is corrupted\n{err}");
+                        let reason = err.to_string().into_bytes();
+                        let _ = sender.send(create_message(0, Function::Close, reason));
                         break;
                     }
-                },
-                Err(err) => eprintln!("Something went wrong in the Stream\n{err}"),
+                    Err(err) => {
+                        error!("ERROR: Lost the Connection to the Container\n{err}");
+                        break;
+                    }
+                }
             }
+            close_connections(&connections);
+            // Dropping the channels of the open streams resets them.
+            streams.write().unwrap().clear();
         });
         let receiver = self.receiver.clone();
+        let connections = self.connection.clone();
         thread::spawn(move || {
             for message in receiver.lock().unwrap().iter() {
-                send_message(&mut write_stream, message).unwrap();
-            }
-        });
-        let receive_connection = self.receiver_connection.clone();
-        let write_connections = self.connection.clone();
-        thread::spawn(move || {
-            for connection in receive_connection.lock().unwrap().iter() {
-                write_connections
-                    .write()
-                    .unwrap()
-                    .insert(connection.port, Arc::new(connection));
+                count_bytes(&connections, &message);
+                let close = message.header.function == Function::Close;
+                if let Err(err) = send_message(&mut write_stream, message) {
+                    error!("ERROR: Unable to send Message to the Container\n{err}");
+                    break;
+                }
+                if close {
+                    let _ = write_stream.shutdown(Shutdown::Write);
+                    break;
+                }
             }
         });
         read_thread.join().unwrap();
     }
-}
 
-fn read_header(stream: &TcpStream) -> Result<Option<Header>, std::io::Error> {
-    let mut header_buffer = [0; 8];
-    let size = stream.take(8).read(&mut header_buffer)?;
-    if size == 0 {
-        return Ok(None);
+    /// The Container of this session, known after the HELLO was accepted.
+    pub fn peer(&self) -> Option<Hello> {
+        self.peer.read().unwrap().clone()
+    }
+
+    /// Sender for messages to the Container, which can be used from any thread.
+    ///
+    /// Sending a `Close` message ends the session, after the Container closed its side.
+    pub fn sender(&self) -> Sender<Message> {
+        self.sender.clone()
+    }
+
+    /// Handle to the forwarded ports of this session, which can be used from any thread.
+    pub fn handle(&self) -> SessionHandle {
+        SessionHandle {
+            session: self.session.clone(),
+        }
     }
-    if size != 8 {
-        return Err(std::io::ErrorKind::InvalidData.into());
+}
+
+fn read_header<R: Read>(stream: &mut R) -> Result<Option<[u8; HEADER_SIZE]>, ProtocolError> {
+    let mut header_buffer = [0; HEADER_SIZE];
+    let mut received = 0;
+    while received < HEADER_SIZE {
+        match stream.read(&mut header_buffer[received..]) {
+            Ok(0) if received == 0 => return Ok(None),
+            Ok(0) => return Err(ProtocolError::TruncatedHeader { received }),
+            Ok(size) => received += size,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
     }
-    Ok(Some(Header::decode(&header_buffer)))
+    Ok(Some(header_buffer))
 }
 
-pub fn read_message(stream: &TcpStream) -> Result<Option<Message>, std::io::Error> {
-    let header = match read_header(stream)? {
-        Some(header) => header,
+/// Reads the next frame, `None` if the other side closed the connection between two frames.
+///
+/// The body is read before the header is decoded, so an unknown function does not break the
+/// framing and the caller is able to continue with the next frame.
+pub fn read_message<R: Read>(mut stream: R) -> Result<Option<Message>, ProtocolError> {
+    let header_buffer = match read_header(&mut stream)? {
+        Some(header_buffer) => header_buffer,
         None => return Ok(None),
     };
-    let mut body = Vec::new();
-    let _size = stream
-        .take(header.message_size.into())
-        .read_to_end(&mut body)
-        .unwrap();
+    let message_size = u32::from_be_bytes([
+        header_buffer[0],
+        header_buffer[1],
+        header_buffer[2],
+        header_buffer[3],
+    ]);
+    if message_size > MAX_MESSAGE_SIZE {
+        return Err(ProtocolError::OversizedFrame {
+            size: message_size,
+            limit: MAX_MESSAGE_SIZE,
+        });
+    }
+    let mut body = Vec::with_capacity(message_size as usize);
+    let received = stream
+        .by_ref()
+        .take(message_size.into())
+        .read_to_end(&mut body)?;
+    if received < message_size as usize {
+        return Err(ProtocolError::TruncatedBody {
+            expected: message_size,
+            received,
+        });
+    }
+    let header = Header::decode(&header_buffer)?;
     let message = Message { header, body };
     Ok(Some(message))
 }
 
-fn send_message(stream: &mut TcpStream, message: Message) -> Result<usize, std::io::Error> {
+/// Writes `message` as one frame, returns the size of the frame.
+pub fn send_message<W: Write>(stream: &mut W, message: Message) -> Result<usize, std::io::Error> {
     let buffer = message.encode();
-    let size = stream.write(&buffer)?;
-    Ok(size)
+    stream.write_all(&buffer)?;
+    Ok(buffer.len())
 }
 
 fn handle_socket_message(
-    connections: Arc<RwLock<HashMap<u16, Arc<Connection>>>>,
+    connections: Connections,
+    streams: &RwLock<HashMap<u32, Sender<Message>>>,
     default: &Sender<Message>,
     message: Message,
 ) {
-    let status = match connections.read().unwrap().get(&message.header.port) {
+    if message.header.stream != 0 {
+        count_bytes(&connections, &message);
+        // Frames of an already finished stream are dropped, the stream was closed on both sides.
+        if let Some(stream) = streams.read().unwrap().get(&message.header.stream) {
+            let _ = stream.send(message);
+        }
+        return;
+    }
+    let key = match message.header.function.protocol() {
+        Some(protocol) => (message.header.port, protocol),
+        None => {
+            error!("ERROR: *handle_socket_message* Wrong Header Function\n{message}\n\n");
+            return;
+        }
+    };
+    if matches!(
+        message.header.function,
+        Function::CloseTcp | Function::CloseUdp
+    ) {
+        let connection = connections.write().unwrap().remove(&key);
+        match connection {
+            Some(connection) => connection.close(),
+            None => info!("Port {:?} {} is not forwarded", key.1, message.header.port),
+        }
+        return;
+    }
+    let status = match connections.read().unwrap().get(&key) {
         Some(connection) => connection.connection.lock().unwrap().send(message),
         None => default.send(message),
     };
     match status {
         Ok(()) => {}
         Err(err) => {
-            eprintln!("ERROR: Something went wrong with the handle_socket_message!\n{err}")
+            error!("ERROR: Something went wrong with the handle_socket_message!\n{err}")
         }
     }
 }
@@ -318,51 +1068,79 @@ mod test_handle_socket_message {
 
     #[test]
     fn handling_default() {
-        let connections: Arc<RwLock<HashMap<u16, Arc<Connection>>>> =
-            Arc::new(RwLock::new(HashMap::new()));
+        let connections: Connections = Arc::new(RwLock::new(HashMap::new()));
         let (sender, receiver) = channel::<Message>();
         let message = Message {
             header: Header {
                 message_size: 0,
                 function: Function::CreateTcp,
                 port: 1234,
+                stream: 0,
             },
             body: Vec::new(),
         };
-        handle_socket_message(connections, &sender, message.clone());
+        let streams = RwLock::new(HashMap::new());
+        handle_socket_message(connections, &streams, &sender, message.clone());
         let res = receiver.recv().unwrap();
         assert_eq!(message, res);
     }
 
     #[test]
     fn handling_connection() {
-        let connections: Arc<RwLock<HashMap<u16, Arc<Connection>>>> =
-            Arc::new(RwLock::new(HashMap::new()));
+        let connections: Connections = Arc::new(RwLock::new(HashMap::new()));
         let (sender, receiver) = channel::<Message>();
         let connection = Connection {
             port: 1234,
-            _host_port: 1234,
-            _protocol: Protocol::TCP,
-            _app: "".to_string(),
+            host_port: 1234,
+            protocol: Protocol::TCP,
+            app: "".to_string(),
+            label: None,
             connection: Mutex::new(sender.clone()),
+            address: "127.0.0.1:1234".parse().unwrap(),
+            closed: Arc::new(AtomicBool::new(false)),
+            listener: Mutex::new(None),
+            watchers: Arc::new(Mutex::new(Vec::new())),
+            stats: Arc::new(PortStats::default()),
         };
         connections
             .write()
             .unwrap()
-            .insert(1234, Arc::new(connection));
+            .insert((1234, Protocol::TCP), Arc::new(connection));
         let message = Message {
             header: Header {
                 message_size: 0,
                 function: Function::CreateTcp,
                 port: 1234,
+                stream: 0,
             },
             body: Vec::new(),
         };
         let (default, _) = channel();
-        handle_socket_message(connections, &default, message.clone());
+        let streams = RwLock::new(HashMap::new());
+        handle_socket_message(connections, &streams, &default, message.clone());
         let res = receiver.recv().unwrap();
         assert_eq!(message, res);
     }
+
+    #[test]
+    fn handling_streams() {
+        let connections: Connections = Arc::new(RwLock::new(HashMap::new()));
+        let (first_sender, first_receiver) = channel::<Message>();
+        let (second_sender, second_receiver) = channel::<Message>();
+        let streams = RwLock::new(HashMap::from([(1, first_sender), (2, second_sender)]));
+        let (default, default_receiver) = channel();
+        let first = create_stream_message(1234, 1, Function::Tcp, b"first".to_vec());
+        let second = create_stream_message(1234, 2, Function::Tcp, b"second".to_vec());
+        let finished = create_stream_message(1234, 3, Function::Tcp, b"finished".to_vec());
+        handle_socket_message(connections.clone(), &streams, &default, second.clone());
+        handle_socket_message(connections.clone(), &streams, &default, first.clone());
+        handle_socket_message(connections, &streams, &default, finished);
+        assert_eq!(first, first_receiver.try_recv().unwrap());
+        assert_eq!(second, second_receiver.try_recv().unwrap());
+        assert!(first_receiver.try_recv().is_err());
+        assert!(second_receiver.try_recv().is_err());
+        assert!(default_receiver.try_recv().is_err());
+    }
 }
 
 #[cfg(test)]
@@ -371,69 +1149,221 @@ mod tests {
 
     #[test]
     fn decode_header_correctly() {
-        let input_header: [u8; 8] = [0, 0, 0, 200, 0b1100, 0x0B, 0xB8, 0];
+        let input_header: [u8; HEADER_SIZE] = [0, 0, 0, 200, 0b1100, 0x0B, 0xB8, 0, 0, 0, 0, 0];
         let expected = Header {
             message_size: 200,
             function: Function::CreateTcp,
             port: 3000,
+            stream: 0,
         };
-        let result = Header::decode(&input_header);
+        let result = Header::decode(&input_header).unwrap();
         assert_eq!(expected.message_size, result.message_size);
         assert_eq!(expected.function, result.function);
         assert_eq!(expected.port, result.port);
     }
 }
 
-fn create_header(port: u16, message_size: u32, function: Function) -> Header {
+fn create_header(port: u16, stream: u32, message_size: u32, function: Function) -> Header {
     Header {
         message_size,
         port,
         function,
+        stream,
     }
 }
 
 pub fn create_message(port: u16, function: Function, message: Vec<u8>) -> Message {
-    let header = create_header(port, message.len() as u32, function);
+    create_stream_message(port, 0, function, message)
+}
+
+pub fn create_stream_message(
+    port: u16,
+    stream: u32,
+    function: Function,
+    message: Vec<u8>,
+) -> Message {
+    let header = create_header(port, stream, message.len() as u32, function);
     Message {
         header,
         body: message,
     }
 }
 
-fn get_socket(port: u16) -> (TcpListener, u16) {
-    match TcpListener::bind(format!("localhost:{port}")) {
-        Ok(socket) => (socket, port),
-        Err(_) => get_socket(port + 1),
+/// Forwards a single TCP connection in both directions until both sides shut down or one
+/// side resets the stream.
+///
+/// Traffic read from `socket` is sent as `Tcp` frames via `multi_sender`, while frames
+/// arriving on `receiver` are written to `socket`. `local_sender` has to feed `receiver` and is
+/// used to wake up the writing side, if the socket fails while reading.
+pub fn forward_tcp_stream(
+    socket: TcpStream,
+    port: u16,
+    stream: u32,
+    multi_sender: Sender<Message>,
+    local_sender: Sender<Message>,
+    receiver: &Receiver<Message>,
+) {
+    let reset = Arc::new(AtomicBool::new(false));
+    let reader = match socket.try_clone() {
+        Ok(read_socket) => {
+            let multi_sender = multi_sender.clone();
+            let reset = reset.clone();
+            thread::spawn(move || {
+                read_tcp_stream(read_socket, port, stream, multi_sender, local_sender, reset)
+            })
+        }
+        Err(err) => {
+            error!("ERROR: Unable to clone Stream {stream} of Port {port}\n{err}");
+            let _ = multi_sender.send(create_stream_message(
+                port,
+                stream,
+                Function::TcpReset,
+                Vec::new(),
+            ));
+            return;
+        }
+    };
+    write_tcp_stream(&socket, port, stream, &multi_sender, receiver, &reset);
+    if reader.join().is_err() {
+        error!("ERROR: Reading Stream {stream} of Port {port} panicked");
+    }
+}
+
+fn read_tcp_stream(
+    mut socket: TcpStream,
+    port: u16,
+    stream: u32,
+    multi_sender: Sender<Message>,
+    local_sender: Sender<Message>,
+    reset: Arc<AtomicBool>,
+) {
+    let mut buffer = [0; STREAM_BUFFER_SIZE];
+    loop {
+        match socket.read(&mut buffer) {
+            Ok(0) => {
+                if !reset.load(Ordering::SeqCst) {
+                    let _ = multi_sender.send(create_stream_message(
+                        port,
+                        stream,
+                        Function::TcpShutdown,
+                        Vec::new(),
+                    ));
+                }
+                break;
+            }
+            Ok(size) => {
+                let message =
+                    create_stream_message(port, stream, Function::Tcp, buffer[..size].to_vec());
+                if multi_sender.send(message).is_err() {
+                    break;
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => {
+                if !reset.swap(true, Ordering::SeqCst) {
+                    error!("ERROR: Unable to read Stream {stream} of Port {port}\n{err}");
+                    let message =
+                        create_stream_message(port, stream, Function::TcpReset, Vec::new());
+                    let _ = multi_sender.send(message.clone());
+                    let _ = local_sender.send(message);
+                }
+                break;
+            }
+        }
+    }
+}
+
+fn write_tcp_stream(
+    mut socket: &TcpStream,
+    port: u16,
+    stream: u32,
+    multi_sender: &Sender<Message>,
+    receiver: &Receiver<Message>,
+    reset: &AtomicBool,
+) {
+    for message in receiver.iter() {
+        if message.header.stream != stream {
+            continue;
+        }
+        match message.header.function {
+            Function::Tcp => {
+                if let Err(err) = socket.write_all(&message.body) {
+                    if !reset.swap(true, Ordering::SeqCst) {
+                        error!("ERROR: Unable to write Stream {stream} of Port {port}\n{err}");
+                        let _ = multi_sender.send(create_stream_message(
+                            port,
+                            stream,
+                            Function::TcpReset,
+                            Vec::new(),
+                        ));
+                    }
+                    let _ = socket.shutdown(Shutdown::Both);
+                    return;
+                }
+            }
+            Function::TcpShutdown => {
+                let _ = socket.shutdown(Shutdown::Write);
+                return;
+            }
+            Function::TcpReset => {
+                reset.store(true, Ordering::SeqCst);
+                let _ = socket.shutdown(Shutdown::Both);
+                return;
+            }
+            _ => error!("ERROR: *write_tcp_stream* Wrong Header Function\n{message}\n\n"),
+        }
     }
+    // The other side of the channel is gone, so nobody will ever finish this stream.
+    reset.store(true, Ordering::SeqCst);
+    let _ = socket.shutdown(Shutdown::Both);
 }
 
 fn tcp_listener(
     socket: TcpListener,
     multi_sender: Sender<Message>,
     label_port: Cell<u16>,
-    _listen_port: Cell<u16>,
-    receiver: Receiver<Message>,
+    stats: Arc<PortStats>,
+    streams: Arc<RwLock<HashMap<u32, Sender<Message>>>>,
+    next_stream: Arc<AtomicU32>,
+    closed: Arc<AtomicBool>,
 ) {
-    let mut buffer = [0; 1024];
     for stream in socket.incoming() {
+        if closed.load(Ordering::SeqCst) {
+            break;
+        }
         match stream {
-            Ok(mut stream) => {
-                let mut message = Vec::new();
-                loop {
-                    let size = stream.read(&mut buffer).unwrap();
-                    message.append(&mut buffer.to_vec());
-                    if size < 1024 {
-                        break;
-                    }
+            Ok(stream) => {
+                let port = label_port.get();
+                let id = next_stream.fetch_add(1, Ordering::SeqCst);
+                let (stream_sender, stream_receiver) = channel();
+                streams.write().unwrap().insert(id, stream_sender.clone());
+                stats.active.fetch_add(1, Ordering::Relaxed);
+                let open = create_stream_message(port, id, Function::TcpOpen, Vec::new());
+                // The Session ended, nobody will ever forward this connection or the next ones.
+                if multi_sender.send(open).is_err() {
+                    streams.write().unwrap().remove(&id);
+                    stats.active.fetch_sub(1, Ordering::Relaxed);
+                    let _ = stream.shutdown(Shutdown::Both);
+                    break;
                 }
-                multi_sender
-                    .send(create_message(label_port.get(), Function::Tcp, message))
-                    .expect("Unable to forward message!");
-                let response = receiver.recv().unwrap();
-                stream.write_all(&response.body).unwrap();
+                let multi_sender = multi_sender.clone();
+                let streams = streams.clone();
+                let stats = stats.clone();
+                thread::spawn(move || {
+                    forward_tcp_stream(
+                        stream,
+                        port,
+                        id,
+                        multi_sender,
+                        stream_sender,
+                        &stream_receiver,
+                    );
+                    streams.write().unwrap().remove(&id);
+                    stats.active.fetch_sub(1, Ordering::Relaxed);
+                });
             }
             Err(err) => {
-                eprintln!("ERROR: TCPListener, unable to read Message\n{err}");
+                error!("ERROR: TCPListener, unable to read Message\n{err}");
                 continue;
             }
         };
@@ -441,54 +1371,1100 @@ fn tcp_listener(
 }
 
 fn setup_tcp_listener(
-    multi_sender: Sender<Message>,
-    message: Message,
-    connection_sender: Sender<Connection>,
-) {
-    let (socket, port) = get_socket(message.header.port);
+    container_port: u16,
+    request: PortRequest,
+    session: &Session,
+) -> Result<SocketAddr, String> {
+    let container = session.container();
+    let bind = session.host_ports.bind.as_str();
+    let preferred = session
+        .host_ports
+        .preferred(&container, container_port, &Protocol::TCP);
+    let bound = session
+        .host_ports
+        .policy
+        .bind(container_port, preferred, &request, |port| {
+            let socket = TcpListener::bind((bind, port))?;
+            let port = socket.local_addr()?.port();
+            Ok((socket, port))
+        });
+    let (socket, port) = match bound {
+        Ok(bound) => bound,
+        Err(err) => {
+            error!(
+                "ERROR: Unable to forward TCP Port {}\n{err}",
+                labeled(container_port, &request)
+            );
+            session.refuse(container_port, &Protocol::TCP, err.clone());
+            return Err(err);
+        }
+    };
+    session
+        .host_ports
+        .assign(container, container_port, Protocol::TCP, port);
+    info!(
+        "Forwarding TCP Port {} to Host Port {port}",
+        labeled(container_port, &request)
+    );
+    let address = socket
+        .local_addr()
+        .expect("Unable to read the address of the listener");
     let (sender, receiver) = channel();
+    let closed = Arc::new(AtomicBool::new(false));
+    let label_port = Cell::new(container_port);
+    let stats = Arc::new(PortStats::default());
+    let listener_stats = stats.clone();
+    let listener_closed = closed.clone();
+    let multi_sender = session.sender.clone();
+    let streams = session.streams.clone();
+    let next_stream = session.next_stream.clone();
+    let listener = thread::spawn(|| {
+        tcp_listener(
+            socket,
+            multi_sender,
+            label_port,
+            listener_stats,
+            streams,
+            next_stream,
+            listener_closed,
+        )
+    });
     let connection = Connection {
-        port: message.header.port,
-        _host_port: port,
-        _protocol: Protocol::TCP,
-        _app: match str::from_utf8(&message.body) {
-            Ok(s) => s.to_string(),
-            Err(_) => "Unkown".to_string(),
-        },
+        port: container_port,
+        host_port: port,
+        protocol: Protocol::TCP,
+        app: request.app,
+        label: request.label,
         connection: Mutex::new(sender),
+        address,
+        closed,
+        listener: Mutex::new(Some(listener)),
+        watchers: session.watchers.clone(),
+        stats,
     };
-    let label_port = Cell::new(message.header.port);
-    let listen_port = Cell::new(port);
-    thread::spawn(|| tcp_listener(socket, multi_sender, label_port, listen_port, receiver));
-    connection_sender.send(connection).unwrap();
+    let port_session = session.clone();
+    thread::spawn(move || handle_port_message(receiver, port_session, address));
+    let forwarded = connection.forwarded();
+    session
+        .connections
+        .write()
+        .unwrap()
+        .insert((container_port, Protocol::TCP), Arc::new(connection));
+    session.reply(
+        container_port,
+        &Protocol::TCP,
+        PortReply::Forwarded(address),
+    );
+    notify(&session.watchers, PortChange::Forwarded(forwarded));
+    Ok(address)
+}
+
+/// Connects a stream of the Container to a port on `localhost` of the Host.
+///
+/// The stream is registered before connecting, so its first frames wait for the connection.
+fn connect_reverse(message: Message, session: &Session) {
+    let (port, id) = (message.header.port, message.header.stream);
+    let reset = create_stream_message(port, id, Function::TcpReset, Vec::new());
+    if id & REVERSE_STREAM == 0 || !session.host_ports.reverse.contains(&port) {
+        error!("ERROR: The Container is not allowed to connect to Port {port} of the Host");
+        let _ = session.sender.send(reset);
+        return;
+    }
+    let (stream_sender, stream_receiver) = channel();
+    session
+        .streams
+        .write()
+        .unwrap()
+        .insert(id, stream_sender.clone());
+    let sender = session.sender.clone();
+    let streams = session.streams.clone();
+    thread::spawn(move || {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+        match TcpStream::connect_timeout(&address, REVERSE_CONNECT_TIMEOUT) {
+            Ok(socket) => {
+                forward_tcp_stream(socket, port, id, sender, stream_sender, &stream_receiver)
+            }
+            Err(err) => {
+                error!("ERROR: Unable to connect to Port {port} of the Host\n{err}");
+                let _ = sender.send(reset);
+            }
+        }
+        streams.write().unwrap().remove(&id);
+    });
 }
 
-fn setup_udp_listener(_multi_sender: Sender<Message>, _message: Message) {
-    println!("Setup UDP Listener");
-    todo!();
+/// Tunnels every connection to `port` in the Container to `host_port` on the Host, as long as
+/// `reverse` is set, i.e. the Host supports it.
+///
+/// Every connection is registered in `streams`, which routes the frames of the Host to it.
+pub fn reverse_listener(
+    listener: TcpListener,
+    port: u16,
+    host_port: u16,
+    session_sender: Arc<Mutex<Sender<Message>>>,
+    streams: Arc<RwLock<HashMap<u32, Sender<Message>>>>,
+    reverse: &AtomicBool,
+) {
+    for socket in listener.incoming() {
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(err) => {
+                error!("ERROR: Unable to accept a connection on Port {port}\n{err}");
+                continue;
+            }
+        };
+        if !reverse.load(Ordering::SeqCst) {
+            error!("ERROR: The Host does not accept connections to Port {host_port}, is it connected and allows reverse?");
+            continue;
+        }
+        let (stream_sender, stream_receiver) = channel();
+        let sender = session_sender.lock().unwrap().clone();
+        let id = {
+            let mut streams = streams.write().unwrap();
+            let id = reverse_stream_id(&NEXT_REVERSE_STREAM, &streams);
+            streams.insert(id, stream_sender.clone());
+            id
+        };
+        let open = create_stream_message(host_port, id, Function::ConnectTcp, Vec::new());
+        if sender.send(open).is_err() {
+            streams.write().unwrap().remove(&id);
+            continue;
+        }
+        let streams = streams.clone();
+        thread::spawn(move || {
+            forward_tcp_stream(
+                socket,
+                host_port,
+                id,
+                sender,
+                stream_sender,
+                &stream_receiver,
+            );
+            streams.write().unwrap().remove(&id);
+        });
+    }
 }
 
-fn handle_unknown_port(
-    receiver: Receiver<Message>,
+/// The next id of `next` with `REVERSE_STREAM` set, skipping the ids of streams still open after
+/// the counter wrapped around.
+fn reverse_stream_id<T>(next: &AtomicU32, streams: &HashMap<u32, T>) -> u32 {
+    loop {
+        let id = REVERSE_STREAM | (next.fetch_add(1, Ordering::SeqCst) & !REVERSE_STREAM);
+        if !streams.contains_key(&id) {
+            return id;
+        }
+    }
+}
+
+fn handle_port_message(receiver: Receiver<Message>, session: Session, address: SocketAddr) {
+    for message in receiver.iter() {
+        match message.header.function {
+            Function::CreateTcp | Function::CreateUdp => {
+                info!("Port {} is already forwarded", message.header.port);
+                if let Some(protocol) = message.header.function.protocol() {
+                    session.reply(
+                        message.header.port,
+                        &protocol,
+                        PortReply::Forwarded(address),
+                    );
+                }
+            }
+            _ => error!("ERROR: *handle_port_message* Wrong Header Function\n{message}\n\n"),
+        }
+    }
+}
+
+/// Tracks the last activity of a UDP session, which is shared between the reading and
+/// writing side of the session.
+#[derive(Clone)]
+pub struct UdpSession {
+    pub stream: u32,
+    last_active: Arc<Mutex<Instant>>,
+}
+
+impl UdpSession {
+    pub fn new(stream: u32) -> UdpSession {
+        UdpSession {
+            stream,
+            last_active: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn touch(&self) {
+        *self.last_active.lock().unwrap() = Instant::now();
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.last_active.lock().unwrap().elapsed() >= UDP_SESSION_TIMEOUT
+    }
+}
+
+fn open_udp_session(
+    socket: &UdpSocket,
+    peer: SocketAddr,
+    streams: &RwLock<HashMap<u32, Sender<Message>>>,
+    next_stream: &AtomicU32,
+) -> Result<UdpSession, std::io::Error> {
+    let reply_socket = socket.try_clone()?;
+    let session = UdpSession::new(next_stream.fetch_add(1, Ordering::SeqCst));
+    let (sender, receiver) = channel::<Message>();
+    streams.write().unwrap().insert(session.stream, sender);
+    let reply_session = session.clone();
+    thread::spawn(move || {
+        for message in receiver.iter() {
+            if let Err(err) = reply_socket.send_to(&message.body, peer) {
+                error!("ERROR: Unable to send Datagram to {peer}\n{err}");
+            }
+            reply_session.touch();
+        }
+    });
+    Ok(session)
+}
+
+fn udp_listener(
+    socket: UdpSocket,
     multi_sender: Sender<Message>,
-    connection_sender: Sender<Connection>,
+    label_port: Cell<u16>,
+    stats: Arc<PortStats>,
+    streams: Arc<RwLock<HashMap<u32, Sender<Message>>>>,
+    next_stream: Arc<AtomicU32>,
+    closed: Arc<AtomicBool>,
 ) {
+    socket
+        .set_read_timeout(Some(UDP_SWEEP_INTERVAL))
+        .expect("Unable to set UDP read timeout");
+    let mut sessions: HashMap<SocketAddr, UdpSession> = HashMap::new();
+    let mut buffer = vec![0; UDP_BUFFER_SIZE];
+    loop {
+        let received = socket.recv_from(&mut buffer);
+        if closed.load(Ordering::SeqCst) {
+            break;
+        }
+        match received {
+            Ok((size, peer)) => {
+                let session = match sessions.get(&peer) {
+                    Some(session) => session,
+                    None => match open_udp_session(&socket, peer, &streams, &next_stream) {
+                        Ok(session) => sessions.entry(peer).or_insert(session),
+                        Err(err) => {
+                            error!("ERROR: Unable to open UDP session for {peer}\n{err}");
+                            continue;
+                        }
+                    },
+                };
+                session.touch();
+                let message = create_stream_message(
+                    label_port.get(),
+                    session.stream,
+                    Function::Udp,
+                    buffer[..size].to_vec(),
+                );
+                if multi_sender.send(message).is_err() {
+                    break;
+                }
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(err) => error!("ERROR: UDPListener, unable to read Datagram\n{err}"),
+        }
+        sessions.retain(|_, session| {
+            if session.is_idle() {
+                streams.write().unwrap().remove(&session.stream);
+                return false;
+            }
+            true
+        });
+        stats.active.store(sessions.len(), Ordering::Relaxed);
+    }
+    for session in sessions.values() {
+        streams.write().unwrap().remove(&session.stream);
+    }
+}
+
+fn setup_udp_listener(
+    container_port: u16,
+    request: PortRequest,
+    session: &Session,
+) -> Result<SocketAddr, String> {
+    let container = session.container();
+    let bind = session.host_ports.bind.as_str();
+    let preferred = session
+        .host_ports
+        .preferred(&container, container_port, &Protocol::UDP);
+    let bound = session
+        .host_ports
+        .policy
+        .bind(container_port, preferred, &request, |port| {
+            let socket = UdpSocket::bind((bind, port))?;
+            let port = socket.local_addr()?.port();
+            Ok((socket, port))
+        });
+    let (socket, port) = match bound {
+        Ok(bound) => bound,
+        Err(err) => {
+            error!(
+                "ERROR: Unable to forward UDP Port {}\n{err}",
+                labeled(container_port, &request)
+            );
+            session.refuse(container_port, &Protocol::UDP, err.clone());
+            return Err(err);
+        }
+    };
+    session
+        .host_ports
+        .assign(container, container_port, Protocol::UDP, port);
+    info!(
+        "Forwarding UDP Port {} to Host Port {port}",
+        labeled(container_port, &request)
+    );
+    let address = socket
+        .local_addr()
+        .expect("Unable to read the address of the listener");
+    let (sender, receiver) = channel();
+    let closed = Arc::new(AtomicBool::new(false));
+    let label_port = Cell::new(container_port);
+    let stats = Arc::new(PortStats::default());
+    let listener_stats = stats.clone();
+    let listener_closed = closed.clone();
+    let multi_sender = session.sender.clone();
+    let streams = session.streams.clone();
+    let next_stream = session.next_stream.clone();
+    let listener = thread::spawn(|| {
+        udp_listener(
+            socket,
+            multi_sender,
+            label_port,
+            listener_stats,
+            streams,
+            next_stream,
+            listener_closed,
+        )
+    });
+    let connection = Connection {
+        port: container_port,
+        host_port: port,
+        protocol: Protocol::UDP,
+        app: request.app,
+        label: request.label,
+        connection: Mutex::new(sender),
+        address,
+        closed,
+        listener: Mutex::new(Some(listener)),
+        watchers: session.watchers.clone(),
+        stats,
+    };
+    let port_session = session.clone();
+    thread::spawn(move || handle_port_message(receiver, port_session, address));
+    let forwarded = connection.forwarded();
+    session
+        .connections
+        .write()
+        .unwrap()
+        .insert((container_port, Protocol::UDP), Arc::new(connection));
+    session.reply(
+        container_port,
+        &Protocol::UDP,
+        PortReply::Forwarded(address),
+    );
+    notify(&session.watchers, PortChange::Forwarded(forwarded));
+    Ok(address)
+}
+
+/// The request of the Container, completed by the settings of the Host.
+///
+/// Fails with the reason, if the devcontainer.json of the Host ignores the port.
+fn port_request(
+    message: &Message,
+    protocol: Protocol,
+    session: &Session,
+) -> Result<PortRequest, String> {
+    let request = PortRequest::decode(&message.body);
+    let devcontainer = match &session.host_ports.devcontainer {
+        Some(devcontainer) => devcontainer,
+        None => return Ok(request),
+    };
+    let port = ListenPort {
+        port: message.header.port,
+        ip: "localhost".to_string(),
+        protocol,
+        app: request.app.clone(),
+    };
+    let attributes = devcontainer.attributes(&port);
+    if attributes.ignored() && !devcontainer.forwards(&port) {
+        info!(
+            "Ignoring Port {:?} {} of {}, onAutoForward is ignore",
+            port.protocol, port.port, port.app
+        );
+        return Err("The devcontainer.json of the Host ignores the port".to_string());
+    }
+    Ok(request.merge(attributes))
+}
+
+/// The port followed by its label, like `3000 (Frontend)`.
+fn labeled(port: u16, request: &PortRequest) -> String {
+    match &request.label {
+        Some(label) => format!("{port} ({label})"),
+        None => port.to_string(),
+    }
+}
+
+fn handle_unknown_port(receiver: Receiver<Message>, session: Session) {
     for message in receiver.iter() {
-        match message.header.function {
-            Function::CreateTcp => {
-                setup_tcp_listener(multi_sender.clone(), message, connection_sender.clone());
+        let protocol = match message.header.function {
+            Function::CreateTcp => Protocol::TCP,
+            Function::CreateUdp => Protocol::UDP,
+            _ => {
+                error!("ERROR: *handle_unknown_port* Wrong Header Function\n{message}\n\n");
+                continue;
             }
-            Function::CreateUdp => setup_udp_listener(multi_sender.clone(), message),
-            _ => eprintln!("ERROR: *handle_unknown_port* Wrong Header Function\n{message}\n\n"),
+        };
+        let port = message.header.port;
+        if session.paused.load(Ordering::SeqCst) {
+            info!("Refusing Port {protocol:?} {port}, auto forwarding is paused");
+            let reason = "Auto forwarding is paused on the Host".to_string();
+            session.refuse(port, &protocol, reason);
+            continue;
+        }
+        match port_request(&message, protocol.clone(), &session) {
+            Ok(request) => {
+                // The reason was logged and sent to the Container already.
+                let _ = session.forward(port, protocol, request);
+            }
+            Err(reason) => session.refuse(port, &protocol, reason),
         }
     }
 }
 
-pub fn client_write_stream(mut stream: TcpStream, receiver: Receiver<Message>) {
+/// Writes all messages to the Host, until the session is closed with a `Close` message or the
+/// connection is lost.
+pub fn client_write_stream<T: Transport>(mut stream: T, receiver: Receiver<Message>) {
     for message in receiver.iter() {
-        match send_message(&mut stream, message) {
-            Ok(_) => {}
-            Err(err) => eprintln!("ERROR: Unable to forward Message:\n{err}"),
+        let close = message.header.function == Function::Close;
+        if let Err(err) = send_message(&mut stream, message) {
+            error!("ERROR: Unable to forward Message:\n{err}");
+            break;
+        }
+        if close {
+            let _ = stream.shutdown(Shutdown::Write);
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_forward_tcp_stream {
+    use super::*;
+
+    #[test]
+    fn forwards_both_directions_with_half_close() {
+        let service = TcpListener::bind("127.0.0.1:0").unwrap();
+        let service_addr = service.local_addr().unwrap();
+        let service_thread = thread::spawn(move || {
+            let (mut socket, _) = service.accept().unwrap();
+            let mut request = Vec::new();
+            socket.read_to_end(&mut request).unwrap();
+            socket.write_all(b"pong").unwrap();
+            request
+        });
+        let entry = TcpListener::bind("127.0.0.1:0").unwrap();
+        let entry_addr = entry.local_addr().unwrap();
+        let (host_sender, host_receiver) = channel();
+        let (container_sender, container_receiver) = channel();
+        let host_thread = {
+            let (host_sender, container_sender) = (host_sender.clone(), container_sender.clone());
+            thread::spawn(move || {
+                let (socket, _) = entry.accept().unwrap();
+                forward_tcp_stream(socket, 80, 1, container_sender, host_sender, &host_receiver);
+            })
         };
+        let container_thread = thread::spawn(move || {
+            let socket = TcpStream::connect(service_addr).unwrap();
+            forward_tcp_stream(
+                socket,
+                80,
+                1,
+                host_sender,
+                container_sender,
+                &container_receiver,
+            );
+        });
+
+        let mut client = TcpStream::connect(entry_addr).unwrap();
+        client.write_all(b"ping").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).unwrap();
+
+        assert_eq!(b"pong".to_vec(), response);
+        assert_eq!(b"ping".to_vec(), service_thread.join().unwrap());
+        host_thread.join().unwrap();
+        container_thread.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_multiplexer {
+    use super::*;
+    use crate::test_util::free_port;
+
+    #[test]
+    fn listener_of_an_ended_session() {
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let (multi_sender, receiver) = channel();
+        drop(receiver);
+        let stats = Arc::new(PortStats::default());
+        let streams = Arc::new(RwLock::new(HashMap::new()));
+        let listener = {
+            let (stats, streams) = (stats.clone(), streams.clone());
+            thread::spawn(move || {
+                tcp_listener(
+                    socket,
+                    multi_sender,
+                    Cell::new(address.port()),
+                    stats,
+                    streams,
+                    Arc::new(AtomicU32::new(0)),
+                    Arc::new(AtomicBool::new(false)),
+                )
+            })
+        };
+        let mut client = TcpStream::connect(address).unwrap();
+        listener.join().unwrap();
+        assert_eq!(0, client.read(&mut [0; 1]).unwrap());
+        assert!(streams.read().unwrap().is_empty());
+        assert_eq!(0, stats.active.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn concurrent_connections_on_one_port() {
+        let host = TcpListener::bind("127.0.0.1:0").unwrap();
+        let container = TcpStream::connect(host.local_addr().unwrap()).unwrap();
+        let (stream, _) = host.accept().unwrap();
+        let multi = Multiplexer::new(stream);
+        let handle = multi.handle();
+        thread::spawn(move || multi.run());
+        send_hello(&container, &Hello::new("test".to_string(), None)).unwrap();
+
+        let port = free_port();
+        let mut writer = container.try_clone().unwrap();
+        send_message(
+            &mut writer,
+            create_message(port, Function::CreateTcp, b"test".to_vec()),
+        )
+        .unwrap();
+        let created = read_message(&container).unwrap().unwrap();
+        assert_eq!(Function::CreatedTcp, created.header.function);
+        assert!(matches!(
+            PortReply::decode(&created.body).unwrap(),
+            PortReply::Forwarded(address) if address.port() == port
+        ));
+
+        let connect = || loop {
+            if let Ok(client) = TcpStream::connect(format!("localhost:{port}")) {
+                return client;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        };
+        let mut first = connect();
+        let first_open = read_message(&container).unwrap().unwrap();
+        let mut second = connect();
+        let second_open = read_message(&container).unwrap().unwrap();
+        assert_eq!(Function::TcpOpen, first_open.header.function);
+        assert_eq!(Function::TcpOpen, second_open.header.function);
+        assert_ne!(first_open.header.stream, second_open.header.stream);
+        assert_eq!(2, handle.ports()[0].connections);
+
+        // Answer in the opposite order, the responses must still reach their own client.
+        for (open, body) in [(&second_open, "second"), (&first_open, "first")] {
+            let stream = open.header.stream;
+            let data = create_stream_message(port, stream, Function::Tcp, body.into());
+            send_message(&mut writer, data).unwrap();
+            let shutdown = create_stream_message(port, stream, Function::TcpShutdown, Vec::new());
+            send_message(&mut writer, shutdown).unwrap();
+        }
+        first.shutdown(Shutdown::Write).unwrap();
+        second.shutdown(Shutdown::Write).unwrap();
+
+        let mut response = String::new();
+        first.read_to_string(&mut response).unwrap();
+        assert_eq!("first", response);
+        response.clear();
+        second.read_to_string(&mut response).unwrap();
+        assert_eq!("second", response);
+        assert_eq!(11, handle.ports()[0].bytes);
+        let start = Instant::now();
+        while handle.ports()[0].connections > 0 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_udp {
+    use super::*;
+
+    #[test]
+    fn function_protocol() {
+        assert_eq!(Some(Protocol::TCP), Function::TcpOpen.protocol());
+        assert_eq!(Some(Protocol::UDP), Function::CreateUdp.protocol());
+        assert_eq!(Some(Protocol::UDP), Function::Udp.protocol());
+        assert_eq!(None, Function::Close.protocol());
+    }
+
+    #[test]
+    fn session_per_peer() {
+        let (multi_sender, multi_receiver) = channel();
+        let streams = Arc::new(RwLock::new(HashMap::new()));
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let listener_streams = streams.clone();
+        let stats = Arc::new(PortStats::default());
+        let listener_stats = stats.clone();
+        thread::spawn(move || {
+            udp_listener(
+                socket,
+                multi_sender,
+                Cell::new(53),
+                listener_stats,
+                listener_streams,
+                Arc::new(AtomicU32::new(1)),
+                Arc::new(AtomicBool::new(false)),
+            )
+        });
+
+        let first = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let second = UdpSocket::bind("127.0.0.1:0").unwrap();
+        first.connect(("127.0.0.1", port)).unwrap();
+        second.connect(("127.0.0.1", port)).unwrap();
+        first.send(b"first").unwrap();
+        let first_message: Message = multi_receiver.recv().unwrap();
+        second.send(b"second").unwrap();
+        let second_message: Message = multi_receiver.recv().unwrap();
+        first.send(b"again").unwrap();
+        let again_message: Message = multi_receiver.recv().unwrap();
+
+        assert_eq!(Function::Udp, first_message.header.function);
+        assert_eq!(53, first_message.header.port);
+        assert_eq!(b"first".to_vec(), first_message.body);
+        assert_eq!(b"second".to_vec(), second_message.body);
+        assert_ne!(first_message.header.stream, second_message.header.stream);
+        assert_eq!(first_message.header.stream, again_message.header.stream);
+        let start = Instant::now();
+        while stats.active.load(Ordering::Relaxed) != 2 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let reply = create_stream_message(
+            53,
+            second_message.header.stream,
+            Function::Udp,
+            b"reply".to_vec(),
+        );
+        streams.read().unwrap()[&second_message.header.stream]
+            .send(reply)
+            .unwrap();
+        let mut buffer = [0; 16];
+        second
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let size = second.recv(&mut buffer).unwrap();
+        assert_eq!(b"reply", &buffer[..size]);
+    }
+}
+
+#[cfg(test)]
+mod test_close {
+    use super::*;
+
+    #[test]
+    fn close_port_frees_host_port() {
+        let host = TcpListener::bind("127.0.0.1:0").unwrap();
+        let container = TcpStream::connect(host.local_addr().unwrap()).unwrap();
+        let (stream, _) = host.accept().unwrap();
+        let multi = Multiplexer::new(stream);
+        let connections = multi.connection.clone();
+        thread::spawn(move || multi.run());
+        send_hello(&container, &Hello::new("test".to_string(), None)).unwrap();
+
+        let port = TcpListener::bind("localhost:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut writer = container.try_clone().unwrap();
+        send_message(
+            &mut writer,
+            create_message(port, Function::CreateTcp, Vec::new()),
+        )
+        .unwrap();
+        while !connections
+            .read()
+            .unwrap()
+            .contains_key(&(port, Protocol::TCP))
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(TcpListener::bind(format!("localhost:{port}")).is_err());
+
+        send_message(
+            &mut writer,
+            create_message(port, Function::CloseTcp, Vec::new()),
+        )
+        .unwrap();
+        let start = Instant::now();
+        while TcpListener::bind(format!("localhost:{port}")).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(!connections
+            .read()
+            .unwrap()
+            .contains_key(&(port, Protocol::TCP)));
+    }
+
+    #[test]
+    fn conflicting_ports_of_two_sessions() {
+        let port = TcpListener::bind("localhost:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut host_ports = Vec::new();
+        let mut containers = Vec::new();
+        for _ in 0..2 {
+            let host = TcpListener::bind("127.0.0.1:0").unwrap();
+            let container = TcpStream::connect(host.local_addr().unwrap()).unwrap();
+            let (stream, _) = host.accept().unwrap();
+            let multi = Multiplexer::new(stream);
+            let connections = multi.connection.clone();
+            thread::spawn(move || multi.run());
+            send_hello(&container, &Hello::new("test".to_string(), None)).unwrap();
+            let mut writer = container.try_clone().unwrap();
+            send_message(
+                &mut writer,
+                create_message(port, Function::CreateTcp, Vec::new()),
+            )
+            .unwrap();
+            let host_port = loop {
+                if let Some(connection) = connections.read().unwrap().get(&(port, Protocol::TCP)) {
+                    break connection.host_port;
+                }
+                thread::sleep(Duration::from_millis(10));
+            };
+            host_ports.push(host_port);
+            containers.push(container);
+        }
+        // The first session wins the port, the second one gets the next free port.
+        assert_eq!(port, host_ports[0]);
+        assert!(host_ports[1] > port);
+
+        // Closing the first session leaves the forward of the second one untouched.
+        containers[0].shutdown(Shutdown::Both).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert!(TcpStream::connect(format!("localhost:{}", host_ports[1])).is_ok());
+    }
+
+    #[test]
+    fn close_session_stops_multiplexer() {
+        let host = TcpListener::bind("127.0.0.1:0").unwrap();
+        let container = TcpStream::connect(host.local_addr().unwrap()).unwrap();
+        let (stream, _) = host.accept().unwrap();
+        let run = thread::spawn(move || Multiplexer::new(stream).run());
+        send_hello(&container, &Hello::new("test".to_string(), None)).unwrap();
+
+        let mut writer = container.try_clone().unwrap();
+        send_message(&mut writer, create_message(0, Function::Close, Vec::new())).unwrap();
+        run.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test_reverse {
+    use super::*;
+
+    #[test]
+    fn skip_open_reverse_streams() {
+        let next = AtomicU32::new(REVERSE_STREAM - 1);
+        let streams = HashMap::from([(REVERSE_STREAM, ()), (REVERSE_STREAM | 2, ())]);
+        assert_eq!(
+            REVERSE_STREAM | (REVERSE_STREAM - 1),
+            reverse_stream_id(&next, &streams)
+        );
+        // Wrapped around, the open streams 0 and 2 keep their ids.
+        assert_eq!(REVERSE_STREAM | 1, reverse_stream_id(&next, &streams));
+        assert_eq!(REVERSE_STREAM | 3, reverse_stream_id(&next, &streams));
+    }
+
+    #[test]
+    fn connect_to_allowed_host_port() {
+        let service = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = service.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut socket, _) = service.accept().unwrap();
+            let mut buffer = [0; 4];
+            socket.read_exact(&mut buffer).unwrap();
+            socket.write_all(&buffer).unwrap();
+        });
+        let host = TcpListener::bind("127.0.0.1:0").unwrap();
+        let container = TcpStream::connect(host.local_addr().unwrap()).unwrap();
+        let (stream, _) = host.accept().unwrap();
+        let multi = Multiplexer::with_host_ports(stream, HostPorts::new().with_reverse(vec![port]));
+        thread::spawn(move || multi.run());
+        let agreed = send_hello(&container, &Hello::new("test".to_string(), None)).unwrap();
+        assert!(agreed.supports("reverse"));
+
+        let mut writer = container.try_clone().unwrap();
+        let id = REVERSE_STREAM | 1;
+        for (function, body) in [
+            (Function::ConnectTcp, Vec::new()),
+            (Function::Tcp, b"ping".to_vec()),
+        ] {
+            send_message(&mut writer, create_stream_message(port, id, function, body)).unwrap();
+        }
+        let echo = read_message(&container).unwrap().unwrap();
+        assert_eq!(
+            (Function::Tcp, id, b"ping".to_vec()),
+            (echo.header.function, echo.header.stream, echo.body)
+        );
+
+        // Ports of the Host, which are not allowed, are reset right away.
+        let denied = REVERSE_STREAM | 2;
+        send_message(
+            &mut writer,
+            create_stream_message(port + 1, denied, Function::ConnectTcp, Vec::new()),
+        )
+        .unwrap();
+        loop {
+            let message = read_message(&container).unwrap().unwrap();
+            if message.header.stream == denied {
+                assert_eq!(Function::TcpReset, message.header.function);
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn two_reverse_ports_at_once() {
+        static REVERSE: AtomicBool = AtomicBool::new(true);
+        // Every service answers with its name, so crossed streams show up in the answers.
+        let services = ["first", "second"].map(|name| {
+            let service = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = service.local_addr().unwrap().port();
+            thread::spawn(move || {
+                for socket in service.incoming() {
+                    let mut socket = socket.unwrap();
+                    let mut request = String::new();
+                    socket.read_to_string(&mut request).unwrap();
+                    socket
+                        .write_all(format!("{name} {request}").as_bytes())
+                        .unwrap();
+                }
+            });
+            port
+        });
+        let host = TcpListener::bind("127.0.0.1:0").unwrap();
+        let container = TcpStream::connect(host.local_addr().unwrap()).unwrap();
+        let (stream, _) = host.accept().unwrap();
+        let multi =
+            Multiplexer::with_host_ports(stream, HostPorts::new().with_reverse(services.to_vec()));
+        thread::spawn(move || multi.run());
+        send_hello(&container, &Hello::new("test".to_string(), None)).unwrap();
+
+        // The agent side: one channel to the Host and one map routing the streams of all ports.
+        let (sender, receiver) = channel();
+        let session_sender = Arc::new(Mutex::new(sender));
+        let streams: Arc<RwLock<HashMap<u32, Sender<Message>>>> = Arc::default();
+        let writer = container.try_clone().unwrap();
+        thread::spawn(move || client_write_stream(writer, receiver));
+        let routes = streams.clone();
+        thread::spawn(move || {
+            while let Ok(Some(message)) = read_message(&container) {
+                if let Some(stream) = routes.read().unwrap().get(&message.header.stream) {
+                    let _ = stream.send(message);
+                }
+            }
+        });
+        let listeners = services.map(|host_port| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let (session_sender, streams) = (session_sender.clone(), streams.clone());
+            thread::spawn(move || {
+                reverse_listener(listener, port, host_port, session_sender, streams, &REVERSE)
+            });
+            port
+        });
+
+        let clients = listeners.map(|port| {
+            let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            client.write_all(format!("to {port}").as_bytes()).unwrap();
+            client
+        });
+        for (mut client, (name, port)) in clients
+            .into_iter()
+            .zip(["first", "second"].iter().zip(listeners))
+        {
+            client.shutdown(Shutdown::Write).unwrap();
+            let mut answer = String::new();
+            client.read_to_string(&mut answer).unwrap();
+            assert_eq!(format!("{name} to {port}"), answer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_host_ports {
+    use super::*;
+    use crate::test_util::free_port;
+
+    fn forward(host_ports: &HostPorts, hostname: &str, port: u16) -> (TcpStream, u16) {
+        let host = TcpListener::bind("127.0.0.1:0").unwrap();
+        let container = TcpStream::connect(host.local_addr().unwrap()).unwrap();
+        let (stream, _) = host.accept().unwrap();
+        let multi = Multiplexer::with_host_ports(stream, host_ports.clone());
+        let connections = multi.connection.clone();
+        thread::spawn(move || multi.run());
+        send_hello(&container, &Hello::new(hostname.to_string(), None)).unwrap();
+        let mut writer = container.try_clone().unwrap();
+        send_message(
+            &mut writer,
+            create_message(port, Function::CreateTcp, Vec::new()),
+        )
+        .unwrap();
+        loop {
+            if let Some(connection) = connections.read().unwrap().get(&(port, Protocol::TCP)) {
+                return (container, connection.host_port);
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn reconnect_keeps_host_port() {
+        let blocker = TcpListener::bind("localhost:0").unwrap();
+        let port = blocker.local_addr().unwrap().port();
+        let host_ports = HostPorts::new();
+        let (container, host_port) = forward(&host_ports, "devbox", port);
+        assert!(host_port > port);
+
+        drop(blocker);
+        container.shutdown(Shutdown::Both).unwrap();
+        let start = Instant::now();
+        while TcpListener::bind(format!("localhost:{host_port}")).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        // The free container port is not taken, the Container gets its previous host port again.
+        let (_container, reconnected_port) = forward(&host_ports, "devbox", port);
+        assert_eq!(host_port, reconnected_port);
+        let (_other, other_port) = forward(&host_ports, "other", port);
+        assert_eq!(port, other_port);
+    }
+
+    #[test]
+    fn devcontainer_of_the_host() {
+        let blocker = TcpListener::bind("localhost:0").unwrap();
+        let required = blocker.local_addr().unwrap().port();
+        let (forwarded, ignored, requested) = (free_port(), free_port(), free_port());
+        let devcontainer = DevContainer::parse(&format!(
+            r#"{{
+                "forwardPorts": [{forwarded}],
+                "portsAttributes": {{
+                    "{required}": {{ "requireLocalPort": true }},
+                    "{ignored}": {{ "onAutoForward": "ignore" }}
+                }}
+            }}"#
+        ))
+        .unwrap();
+        let host = TcpListener::bind("127.0.0.1:0").unwrap();
+        let container = TcpStream::connect(host.local_addr().unwrap()).unwrap();
+        let (stream, _) = host.accept().unwrap();
+        let multi =
+            Multiplexer::with_host_ports(stream, HostPorts::new().with_devcontainer(devcontainer));
+        let connections = multi.connection.clone();
+        thread::spawn(move || multi.run());
+        send_hello(&container, &Hello::new("devbox".to_string(), None)).unwrap();
+        let mut writer = container.try_clone().unwrap();
+        for port in [required, ignored, requested] {
+            send_message(
+                &mut writer,
+                create_message(port, Function::CreateTcp, Vec::new()),
+            )
+            .unwrap();
+        }
+        // Requests are answered in order, the Host forwards its own ports first.
+        for (port, expected) in [
+            (forwarded, true),
+            (required, false),
+            (ignored, false),
+            (requested, true),
+        ] {
+            let created = read_message(&container).unwrap().unwrap();
+            assert_eq!(Function::CreatedTcp, created.header.function);
+            assert_eq!(port, created.header.port);
+            let reply = PortReply::decode(&created.body).unwrap();
+            assert_eq!(expected, matches!(reply, PortReply::Forwarded(_)));
+        }
+        let connections = connections.read().unwrap();
+        assert!(connections.contains_key(&(forwarded, Protocol::TCP)));
+        assert!(!connections.contains_key(&(required, Protocol::TCP)));
+    }
+}
+
+#[cfg(test)]
+mod test_read_message {
+    use super::*;
+
+    #[test]
+    fn read_complete_frame() {
+        let message = create_stream_message(3000, 7, Function::Tcp, b"data".to_vec());
+        let buffer = message.encode();
+        assert_eq!(Some(message), read_message(&buffer[..]).unwrap());
+        assert_eq!(None, read_message(&[][..]).unwrap());
+    }
+
+    #[test]
+    fn skip_unknown_function() {
+        let mut buffer = create_message(3000, Function::Tcp, b"unknown".to_vec()).encode();
+        buffer[4] = 0b1111_1111;
+        let next = create_message(3000, Function::CreateTcp, Vec::new());
+        buffer.append(&mut next.encode());
+        let mut stream = &buffer[..];
+        let err = read_message(&mut stream).unwrap_err();
+        assert!(matches!(err, ProtocolError::UnknownFunction(0b1111_1111)));
+        assert!(err.is_recoverable());
+        assert_eq!(Some(next), read_message(&mut stream).unwrap());
+    }
+
+    #[test]
+    fn truncated_header() {
+        let buffer = create_message(3000, Function::Tcp, Vec::new()).encode();
+        assert!(matches!(
+            read_message(&buffer[..5]),
+            Err(ProtocolError::TruncatedHeader { received: 5 })
+        ));
+    }
+
+    #[test]
+    fn truncated_body() {
+        let buffer = create_message(3000, Function::Tcp, b"data".to_vec()).encode();
+        assert!(matches!(
+            read_message(&buffer[..HEADER_SIZE + 2]),
+            Err(ProtocolError::TruncatedBody {
+                expected: 4,
+                received: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn oversized_frame() {
+        let mut buffer = create_message(3000, Function::Tcp, Vec::new()).encode();
+        buffer[..4].copy_from_slice(&(MAX_MESSAGE_SIZE + 1).to_be_bytes());
+        let err = read_message(&buffer[..]).unwrap_err();
+        assert!(matches!(err, ProtocolError::OversizedFrame { .. }));
+        assert!(!err.is_recoverable());
     }
 }
@@ -0,0 +1,126 @@
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Mutex;
+
+/// Number of lines kept for `recent_log`.
+const RECENT_LINES: usize = 200;
+
+/// Amount of output of both Programs, errors are always printed.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    #[default]
+    Info,
+}
+
+impl LogLevel {
+    pub fn decode(string: &str) -> Result<LogLevel, String> {
+        match string.to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "info" => Ok(LogLevel::Info),
+            _ => Err(format!(
+                "ERROR: Log level {string} is not defined, use error or info"
+            )),
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+pub fn set_log_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn log_level() -> LogLevel {
+    match LEVEL.load(Ordering::Relaxed) {
+        level if level == LogLevel::Error as u8 => LogLevel::Error,
+        _ => LogLevel::Info,
+    }
+}
+
+static STDERR: AtomicBool = AtomicBool::new(false);
+
+/// Prints the `INFO:` lines to stderr as well, e.g. while stdout carries the Session.
+pub fn set_log_stderr(stderr: bool) {
+    STDERR.store(stderr, Ordering::Relaxed);
+}
+
+pub fn log_stderr() -> bool {
+    STDERR.load(Ordering::Relaxed)
+}
+
+static RECENT: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+static FOLLOWERS: Mutex<Vec<Sender<String>>> = Mutex::new(Vec::new());
+
+/// Keeps a printed line for `recent_log` and passes it on to the followers.
+#[doc(hidden)]
+pub fn publish_log(line: String) {
+    {
+        let mut recent = RECENT.lock().unwrap();
+        if recent.len() == RECENT_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(line.clone());
+    }
+    FOLLOWERS
+        .lock()
+        .unwrap()
+        .retain(|follower| follower.send(line.clone()).is_ok());
+}
+
+/// The last printed lines, the oldest first.
+pub fn recent_log() -> Vec<String> {
+    RECENT.lock().unwrap().iter().cloned().collect()
+}
+
+/// Receives every line printed from now on.
+pub fn follow_log() -> Receiver<String> {
+    let (sender, receiver) = channel();
+    FOLLOWERS.lock().unwrap().push(sender);
+    receiver
+}
+
+/// Prints an `INFO:` line, unless the log level only allows errors.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log_level() == $crate::LogLevel::Info {
+            let line = format!("INFO: {}", format_args!($($arg)*));
+            match $crate::log_stderr() {
+                true => eprintln!("{line}"),
+                false => println!("{line}"),
+            }
+            $crate::publish_log(line);
+        }
+    };
+}
+
+/// Prints an error to stderr, like `eprintln`, the message carries its own `ERROR:` prefix.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {{
+        let line = format!($($arg)*);
+        eprintln!("{line}");
+        $crate::publish_log(line);
+    }};
+}
+
+#[cfg(test)]
+mod test_log {
+    use super::*;
+
+    #[test]
+    fn follow_lines() {
+        let follower = follow_log();
+        error!("ERROR: Port {} is taken", 3000);
+        info!("Forwarding Port {}", 3000);
+        // Other tests log at the same time.
+        let lines = follower.try_iter().collect::<Vec<String>>();
+        assert!(lines.contains(&"ERROR: Port 3000 is taken".to_string()));
+        assert!(recent_log().contains(&"ERROR: Port 3000 is taken".to_string()));
+        assert!(recent_log().len() <= RECENT_LINES);
+    }
+}
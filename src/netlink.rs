@@ -0,0 +1,304 @@
+use crate::proc_net::{service_ip, socket_owners};
+use crate::{ListenPort, Protocol};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::{FromRawFd, OwnedFd};
+use std::path::Path;
+
+// Constants of the kernel headers `linux/netlink.h`, `linux/sock_diag.h` and `linux/tcp.h`.
+const NETLINK_SOCK_DIAG: i32 = 4;
+const SOCK_DIAG_BY_FAMILY: u16 = 20;
+const NLM_F_REQUEST: u16 = 0x01;
+const NLM_F_DUMP: u16 = 0x300;
+const NLMSG_ERROR: u16 = 0x02;
+const NLMSG_DONE: u16 = 0x03;
+const TCP_LISTEN: u8 = 10;
+const TCP_CLOSE: u8 = 7;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const AF_INET: u8 = 2;
+const AF_INET6: u8 = 10;
+
+/// Size of `struct nlmsghdr`.
+const NLMSG_HEADER_SIZE: usize = 16;
+
+/// Size of `struct inet_diag_msg`, the answer for a single socket.
+const INET_DIAG_MSG_SIZE: usize = 72;
+
+/// Large enough for a batch of answers, the kernel never splits a single message.
+const RECEIVE_BUFFER_SIZE: usize = 32 * 1024;
+
+/// A listening socket reported by the kernel.
+#[derive(Debug, PartialEq)]
+struct DiagSocket {
+    ip: IpAddr,
+    port: u16,
+    inode: u32,
+}
+
+/// Finds the listening sockets of all processes, by asking the kernel through a
+/// `NETLINK_SOCK_DIAG` socket.
+///
+/// Compared to reading `/proc/net`, only the listening sockets are transferred, which makes it
+/// cheap enough to be called several times a second.
+///
+/// `owners` caches the process of every socket inode between scans, `/proc` is only searched
+/// while a socket is new.
+pub fn scan_sock_diag(owners: &mut HashMap<u32, String>) -> Result<Vec<ListenPort>, io::Error> {
+    let mut socket = open_sock_diag()?;
+    let mut sockets = Vec::new();
+    for (protocol, state) in [(Protocol::TCP, TCP_LISTEN), (Protocol::UDP, TCP_CLOSE)] {
+        for family in [AF_INET, AF_INET6] {
+            for socket in dump(&mut socket, family, &protocol, state)? {
+                sockets.push((protocol.clone(), socket));
+            }
+        }
+    }
+    if sockets
+        .iter()
+        .any(|(_, socket)| !owners.contains_key(&socket.inode))
+    {
+        let found = socket_owners(Path::new("/proc"));
+        for (_, socket) in &sockets {
+            owners
+                .entry(socket.inode)
+                .or_insert_with(|| match found.get(&socket.inode.into()) {
+                    Some(app) => app.clone(),
+                    None => "Unknown".to_string(),
+                });
+        }
+    }
+    // Inodes are reused, closed sockets must not lend their process to new ones.
+    owners.retain(|inode, _| sockets.iter().any(|(_, socket)| socket.inode == *inode));
+    Ok(sockets
+        .into_iter()
+        .map(|(protocol, socket)| ListenPort {
+            port: socket.port,
+            ip: service_ip(&socket.ip),
+            protocol,
+            app: owners[&socket.inode].clone(),
+        })
+        .collect())
+}
+
+fn open_sock_diag() -> Result<File, io::Error> {
+    // SAFETY: socket only takes integer arguments.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+            NETLINK_SOCK_DIAG,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: the descriptor was just created and is owned by nobody else.
+    Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+/// Requests all sockets of `family` and `protocol` in `state`.
+fn dump(
+    socket: &mut File,
+    family: u8,
+    protocol: &Protocol,
+    state: u8,
+) -> Result<Vec<DiagSocket>, io::Error> {
+    let protocol = match protocol {
+        Protocol::TCP => IPPROTO_TCP,
+        Protocol::UDP => IPPROTO_UDP,
+    };
+    // Netlink sockets, which are not bound to an address, send to the kernel.
+    socket.write_all(&encode_request(family, protocol, state))?;
+    let mut sockets = Vec::new();
+    let mut buffer = vec![0; RECEIVE_BUFFER_SIZE];
+    loop {
+        let size = socket.read(&mut buffer)?;
+        if size == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        if decode_response(&buffer[..size], &mut sockets)? {
+            return Ok(sockets);
+        }
+    }
+}
+
+/// Encodes a `struct nlmsghdr` followed by a `struct inet_diag_req_v2`.
+fn encode_request(family: u8, protocol: u8, state: u8) -> Vec<u8> {
+    let length = NLMSG_HEADER_SIZE + 56;
+    let mut request = Vec::with_capacity(length);
+    request.extend_from_slice(&(length as u32).to_ne_bytes());
+    request.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+    request.extend_from_slice(&(NLM_F_REQUEST | NLM_F_DUMP).to_ne_bytes());
+    // Sequence number and port id are not needed, the socket is used for one request at a time.
+    request.extend_from_slice(&[0; 8]);
+    request.extend_from_slice(&[family, protocol, 0, 0]);
+    request.extend_from_slice(&(1u32 << state).to_ne_bytes());
+    // An empty `struct inet_diag_sockid` matches every socket.
+    request.resize(length, 0);
+    request
+}
+
+/// Decodes a batch of answers into `sockets`, returns true once the dump is complete.
+fn decode_response(mut buffer: &[u8], sockets: &mut Vec<DiagSocket>) -> Result<bool, io::Error> {
+    while buffer.len() >= NLMSG_HEADER_SIZE {
+        let length = u32::from_ne_bytes(buffer[0..4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(buffer[4..6].try_into().unwrap());
+        if length < NLMSG_HEADER_SIZE || length > buffer.len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Truncated netlink message",
+            ));
+        }
+        let payload = &buffer[NLMSG_HEADER_SIZE..length];
+        match kind {
+            NLMSG_DONE => return Ok(true),
+            NLMSG_ERROR => {
+                let errno = payload
+                    .get(0..4)
+                    .map(|errno| i32::from_ne_bytes(errno.try_into().unwrap()))
+                    .unwrap_or(0);
+                return Err(io::Error::from_raw_os_error(-errno));
+            }
+            _ => {
+                if let Some(socket) = decode_socket(payload) {
+                    sockets.push(socket);
+                }
+            }
+        }
+        // Messages are aligned to 4 bytes.
+        let aligned = (length + 3) & !3;
+        buffer = &buffer[aligned.min(buffer.len())..];
+    }
+    Ok(false)
+}
+
+/// Decodes a `struct inet_diag_msg`, addresses and ports are in network byte order.
+fn decode_socket(payload: &[u8]) -> Option<DiagSocket> {
+    if payload.len() < INET_DIAG_MSG_SIZE {
+        return None;
+    }
+    let port = u16::from_be_bytes([payload[4], payload[5]]);
+    let ip = match payload[0] {
+        AF_INET => IpAddr::V4(Ipv4Addr::new(
+            payload[8],
+            payload[9],
+            payload[10],
+            payload[11],
+        )),
+        AF_INET6 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&payload[8..24]).ok()?)),
+        _ => return None,
+    };
+    let inode = u32::from_ne_bytes(payload[68..72].try_into().unwrap());
+    Some(DiagSocket { ip, port, inode })
+}
+
+#[cfg(test)]
+mod test_netlink {
+    use super::*;
+    use std::net::{TcpListener, UdpSocket};
+
+    fn diag_message(family: u8, port: u16, ip: &[u8], inode: u32) -> Vec<u8> {
+        let mut payload = vec![0; INET_DIAG_MSG_SIZE];
+        payload[0] = family;
+        payload[1] = TCP_LISTEN;
+        payload[4..6].copy_from_slice(&port.to_be_bytes());
+        payload[8..8 + ip.len()].copy_from_slice(ip);
+        payload[68..72].copy_from_slice(&inode.to_ne_bytes());
+        let mut message = Vec::new();
+        message.extend_from_slice(&((NLMSG_HEADER_SIZE + payload.len()) as u32).to_ne_bytes());
+        message.extend_from_slice(&SOCK_DIAG_BY_FAMILY.to_ne_bytes());
+        message.extend_from_slice(&[0; 10]);
+        message.append(&mut payload);
+        message
+    }
+
+    #[test]
+    fn request_layout() {
+        let request = encode_request(AF_INET6, IPPROTO_TCP, TCP_LISTEN);
+        assert_eq!(72, request.len());
+        assert_eq!(72, u32::from_ne_bytes(request[0..4].try_into().unwrap()));
+        assert_eq!([AF_INET6, IPPROTO_TCP], request[16..18]);
+        assert_eq!(
+            1 << 10,
+            u32::from_ne_bytes(request[20..24].try_into().unwrap())
+        );
+    }
+
+    #[test]
+    fn decode_batch() {
+        let mut batch = diag_message(AF_INET, 8080, &[127, 0, 0, 1], 4242);
+        batch.append(&mut diag_message(AF_INET6, 3000, &[0; 16], 4243));
+        let mut sockets = Vec::new();
+        assert!(!decode_response(&batch, &mut sockets).unwrap());
+        assert_eq!(
+            vec![
+                DiagSocket {
+                    ip: IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    port: 8080,
+                    inode: 4242
+                },
+                DiagSocket {
+                    ip: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                    port: 3000,
+                    inode: 4243
+                },
+            ],
+            sockets
+        );
+
+        let mut done = vec![0; NLMSG_HEADER_SIZE];
+        done[0..4].copy_from_slice(&(NLMSG_HEADER_SIZE as u32).to_ne_bytes());
+        done[4..6].copy_from_slice(&NLMSG_DONE.to_ne_bytes());
+        assert!(decode_response(&done, &mut sockets).unwrap());
+        assert!(decode_response(&batch[..20], &mut sockets).is_err());
+    }
+
+    #[test]
+    fn scan_own_sockets() {
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let ports = match scan_sock_diag(&mut HashMap::new()) {
+            Ok(ports) => ports,
+            // Some sandboxes do not allow netlink sockets at all.
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => return,
+            Err(err) => panic!("{err}"),
+        };
+        let find = |port: u16, protocol: Protocol| {
+            ports
+                .iter()
+                .find(|p| p.port == port && p.protocol == protocol)
+                .cloned()
+        };
+        let tcp = find(tcp.local_addr().unwrap().port(), Protocol::TCP).unwrap();
+        assert_eq!("127.0.0.1", tcp.ip);
+        assert_ne!("Unknown", tcp.app);
+        assert!(find(udp.local_addr().unwrap().port(), Protocol::UDP).is_some());
+    }
+
+    #[test]
+    fn cache_owners() {
+        let first = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut owners = HashMap::new();
+        match scan_sock_diag(&mut owners) {
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => return,
+            Err(err) => panic!("{err}"),
+        }
+        // Known sockets keep their cached process, only the new one is looked up.
+        for app in owners.values_mut() {
+            *app = "cached".to_string();
+        }
+        let second = TcpListener::bind("127.0.0.1:0").unwrap();
+        let ports = scan_sock_diag(&mut owners).unwrap();
+        let app = |listener: &TcpListener| {
+            let port = listener.local_addr().unwrap().port();
+            ports.iter().find(|p| p.port == port).unwrap().app.clone()
+        };
+        assert_eq!("cached", app(&first));
+        assert_ne!("cached", app(&second));
+        assert_ne!("Unknown", app(&second));
+    }
+}
@@ -0,0 +1,228 @@
+use crate::PortRequest;
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+
+/// Ports below this one can only be bound by privileged processes.
+const PRIVILEGED_PORTS: u16 = 1024;
+
+/// How the Host picks the host port of a forwarded port.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub enum PortPolicy {
+    /// The same port as in the Container, otherwise the port is not forwarded.
+    SameOrFail,
+    /// The same port, otherwise the next free port above it.
+    #[default]
+    SameOrNext,
+    /// The same port, otherwise a free port picked by the operating system.
+    SameOrEphemeral,
+    /// The host port of the table, a host port of `0` is picked by the operating system.
+    ///
+    /// Ports missing in the table need the same port on the Host.
+    Fixed(HashMap<u16, u16>),
+}
+
+impl PortPolicy {
+    /// Parses a policy like `same-or-next`, `fixed` takes a table like `["3000:8080"]`.
+    pub fn parse(name: &str, table: &[String]) -> Result<PortPolicy, String> {
+        let policy = match name {
+            "same-or-fail" => PortPolicy::SameOrFail,
+            "same-or-next" => PortPolicy::SameOrNext,
+            "same-or-ephemeral" => PortPolicy::SameOrEphemeral,
+            "fixed" => {
+                let mut ports = HashMap::new();
+                for entry in table {
+                    let (port, host_port) = entry
+                        .split_once(':')
+                        .and_then(|(port, host_port)| {
+                            Some((port.parse::<u16>().ok()?, host_port.parse::<u16>().ok()?))
+                        })
+                        .ok_or(format!(
+                            "ERROR: \"{entry}\" is no mapping like <container port>:<host port>"
+                        ))?;
+                    ports.insert(port, host_port);
+                }
+                return Ok(PortPolicy::Fixed(ports));
+            }
+            _ => {
+                return Err(format!(
+                    "ERROR: Port policy {name} is not defined, use same-or-fail, same-or-next, same-or-ephemeral or fixed"
+                ))
+            }
+        };
+        match table.is_empty() {
+            true => Ok(policy),
+            false => Err("ERROR: A port map requires the fixed port policy".to_string()),
+        }
+    }
+
+    /// Binds the host port of the container `port` with `bind`, which returns the socket together
+    /// with the port it is bound to.
+    ///
+    /// `preferred` is tried first, it differs from `port` if the Container had another host port
+    /// in a previous session.
+    pub(crate) fn bind<S, F>(
+        &self,
+        port: u16,
+        preferred: u16,
+        request: &PortRequest,
+        bind: F,
+    ) -> Result<(S, u16), String>
+    where
+        F: Fn(u16) -> io::Result<(S, u16)>,
+    {
+        if port == 0 {
+            return Err("ERROR: Port 0 can not be forwarded".to_string());
+        }
+        let (wanted, policy) = match (request.host_port, self) {
+            (Some(host_port), _) => (host_port, &PortPolicy::SameOrFail),
+            (None, _) if request.require_local_port => (port, &PortPolicy::SameOrFail),
+            (None, PortPolicy::Fixed(table)) => {
+                (*table.get(&port).unwrap_or(&port), &PortPolicy::SameOrFail)
+            }
+            (None, policy) => (preferred, policy),
+        };
+        let remembered = std::ptr::eq(policy, self) && preferred != port;
+        let (mut tried, mut result) = (wanted, bind(wanted));
+        // A previous host port, which is taken by now, is given up for the port itself.
+        if result.is_err() && remembered {
+            (tried, result) = (port, bind(port));
+        }
+        let err = match result {
+            Ok(socket) => return Ok(socket),
+            Err(err) => err,
+        };
+        if is_denied(tried, &err) && request.elevate_if_needed {
+            return Err(unavailable(tried, err));
+        }
+        match policy {
+            PortPolicy::SameOrNext => {
+                let mut candidate = port.checked_add(1);
+                while let Some(next) = candidate {
+                    match bind(next) {
+                        Ok(socket) => return Ok(socket),
+                        // The remaining privileged ports are denied as well.
+                        Err(err) if is_denied(next, &err) => candidate = Some(PRIVILEGED_PORTS),
+                        Err(_) => candidate = next.checked_add(1),
+                    }
+                }
+                Err(format!("ERROR: No free Host Port above {port}"))
+            }
+            PortPolicy::SameOrEphemeral => bind(0).map_err(|err| unavailable(0, err)),
+            _ => Err(unavailable(tried, err)),
+        }
+    }
+}
+
+fn is_denied(port: u16, err: &io::Error) -> bool {
+    port < PRIVILEGED_PORTS && err.kind() == ErrorKind::PermissionDenied
+}
+
+fn unavailable(port: u16, err: io::Error) -> String {
+    match is_denied(port, &err) {
+        true => format!(
+            "ERROR: Host Port {port} is privileged, the Host needs root or CAP_NET_BIND_SERVICE to bind it\n{err}"
+        ),
+        false => format!("ERROR: Host Port {port} is not available\n{err}"),
+    }
+}
+
+#[cfg(test)]
+mod test_port_policy {
+    use super::*;
+
+    /// Binds like an unprivileged process, with `taken` being in use and `40000` as ephemeral port.
+    fn bind(taken: &[u16]) -> impl Fn(u16) -> io::Result<((), u16)> + '_ {
+        move |port| match port {
+            0 => Ok(((), 40000)),
+            port if taken.contains(&port) => Err(ErrorKind::AddrInUse.into()),
+            port if port < PRIVILEGED_PORTS => Err(ErrorKind::PermissionDenied.into()),
+            port => Ok(((), port)),
+        }
+    }
+
+    fn request() -> PortRequest {
+        PortRequest::decode(b"app=node")
+    }
+
+    fn host_port(policy: &PortPolicy, port: u16, preferred: u16, taken: &[u16]) -> Option<u16> {
+        policy
+            .bind(port, preferred, &request(), bind(taken))
+            .ok()
+            .map(|(_, port)| port)
+    }
+
+    #[test]
+    fn parse_policy() {
+        assert_eq!(
+            PortPolicy::SameOrEphemeral,
+            PortPolicy::parse("same-or-ephemeral", &[]).unwrap()
+        );
+        assert_eq!(
+            PortPolicy::Fixed(HashMap::from([(3000, 8080)])),
+            PortPolicy::parse("fixed", &["3000:8080".to_string()]).unwrap()
+        );
+        assert!(PortPolicy::parse("random", &[]).is_err());
+        assert!(PortPolicy::parse("fixed", &["3000".to_string()]).is_err());
+        assert!(PortPolicy::parse("same-or-next", &["3000:8080".to_string()]).is_err());
+    }
+
+    #[test]
+    fn same_or_next() {
+        let policy = PortPolicy::SameOrNext;
+        assert_eq!(Some(3000), host_port(&policy, 3000, 3000, &[]));
+        assert_eq!(Some(3002), host_port(&policy, 3000, 3000, &[3000, 3001]));
+        // The previous host port is taken, but the port itself is free.
+        assert_eq!(Some(3000), host_port(&policy, 3000, 3005, &[3005]));
+        assert_eq!(None, host_port(&policy, 65535, 65535, &[65535]));
+        assert_eq!(Some(1024), host_port(&policy, 80, 80, &[]));
+        assert_eq!(None, host_port(&policy, 0, 0, &[]));
+    }
+
+    #[test]
+    fn same_or_fail_and_ephemeral() {
+        assert_eq!(
+            None,
+            host_port(&PortPolicy::SameOrFail, 3000, 3000, &[3000])
+        );
+        assert_eq!(None, host_port(&PortPolicy::SameOrFail, 80, 80, &[]));
+        let ephemeral = PortPolicy::SameOrEphemeral;
+        assert_eq!(Some(3000), host_port(&ephemeral, 3000, 3000, &[]));
+        assert_eq!(Some(40000), host_port(&ephemeral, 3000, 3000, &[3000]));
+        assert_eq!(Some(3000), host_port(&ephemeral, 3000, 3005, &[3005]));
+    }
+
+    #[test]
+    fn fixed_table() {
+        let policy = PortPolicy::Fixed(HashMap::from([(3000, 8080), (5000, 0)]));
+        assert_eq!(Some(8080), host_port(&policy, 3000, 3000, &[]));
+        assert_eq!(None, host_port(&policy, 3000, 3000, &[8080]));
+        assert_eq!(Some(40000), host_port(&policy, 5000, 5000, &[]));
+        assert_eq!(Some(4000), host_port(&policy, 4000, 4000, &[]));
+        assert_eq!(None, host_port(&policy, 4000, 4000, &[4000]));
+    }
+
+    #[test]
+    fn request_overrides_policy() {
+        let required = PortRequest {
+            require_local_port: true,
+            ..request()
+        };
+        let policy = PortPolicy::SameOrNext;
+        assert!(policy.bind(3000, 3000, &required, bind(&[3000])).is_err());
+        let elevate = PortRequest {
+            elevate_if_needed: true,
+            ..request()
+        };
+        let err = policy.bind(80, 80, &elevate, bind(&[])).unwrap_err();
+        assert!(err.contains("privileged"));
+        let host_port = PortRequest {
+            host_port: Some(8080),
+            ..request()
+        };
+        assert_eq!(
+            8080,
+            policy.bind(3000, 3005, &host_port, bind(&[])).unwrap().1
+        );
+        assert!(policy.bind(3000, 3000, &host_port, bind(&[8080])).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+use crate::{ListenPort, Protocol};
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// State of a listening TCP socket in `/proc/net/tcp`.
+const TCP_LISTEN: &str = "0A";
+
+/// UDP sockets without a connected peer are reported in the close state.
+const UDP_UNCONNECTED: &str = "07";
+
+/// Socket tables of the kernel, with the protocol of their sockets.
+const SOCKET_TABLES: [(&str, Protocol); 4] = [
+    ("tcp", Protocol::TCP),
+    ("tcp6", Protocol::TCP),
+    ("udp", Protocol::UDP),
+    ("udp6", Protocol::UDP),
+];
+
+/// A listening socket read from one of the socket tables.
+#[derive(Debug, PartialEq)]
+struct ProcSocket {
+    ip: IpAddr,
+    port: u16,
+    protocol: Protocol,
+    inode: u64,
+}
+
+/// Finds the listening sockets of all processes, by reading the socket tables below `proc`.
+///
+/// `proc` is the mount point of procfs, usually `/proc`. Sockets of processes, which are not
+/// accessible to the current user, are reported with an unknown app.
+pub fn scan_proc_net(proc: &Path) -> Vec<ListenPort> {
+    let mut sockets = Vec::new();
+    for (table, protocol) in SOCKET_TABLES {
+        // A kernel without IPv6 support has no tcp6 and udp6 tables.
+        if let Ok(content) = fs::read_to_string(proc.join("net").join(table)) {
+            sockets.append(&mut parse_socket_table(&content, protocol));
+        }
+    }
+    let owners = socket_owners(proc);
+    sockets
+        .into_iter()
+        .map(|socket| ListenPort {
+            port: socket.port,
+            ip: service_ip(&socket.ip),
+            app: match owners.get(&socket.inode) {
+                Some(app) => app.clone(),
+                None => "Unknown".to_string(),
+            },
+            protocol: socket.protocol,
+        })
+        .collect()
+}
+
+/// The address used to connect to the service, in the same notation as lsof uses it.
+pub(crate) fn service_ip(ip: &IpAddr) -> String {
+    match ip {
+        ip if ip.is_unspecified() => "localhost".to_string(),
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("[{ip}]"),
+    }
+}
+
+fn parse_socket_table(content: &str, protocol: Protocol) -> Vec<ProcSocket> {
+    let listening = match protocol {
+        Protocol::TCP => TCP_LISTEN,
+        Protocol::UDP => UDP_UNCONNECTED,
+    };
+    // The first line contains the column names.
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|row| {
+            let columns = row.split_whitespace().collect::<Vec<&str>>();
+            if columns.len() < 10 || columns[3] != listening {
+                return None;
+            }
+            let (ip, port) = parse_address(columns[1])?;
+            let inode = columns[9].parse::<u64>().ok()?;
+            Some(ProcSocket {
+                ip,
+                port,
+                protocol: protocol.clone(),
+                inode,
+            })
+        })
+        .collect()
+}
+
+/// Decodes an address like `0100007F:1F90`.
+///
+/// The kernel prints the address as 32 bit words in host byte order, but the port in network
+/// byte order.
+fn parse_address(address: &str) -> Option<(IpAddr, u16)> {
+    let (ip, port) = address.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut bytes = Vec::with_capacity(16);
+    for word in 0..ip.len() / 8 {
+        let word = u32::from_str_radix(ip.get(word * 8..word * 8 + 8)?, 16).ok()?;
+        bytes.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3])),
+        16 => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => return None,
+    };
+    Some((ip, port))
+}
+
+/// Maps the inode of every socket to the name of the process holding it open.
+pub(crate) fn socket_owners(proc: &Path) -> HashMap<u64, String> {
+    let mut owners = HashMap::new();
+    let processes = match fs::read_dir(proc) {
+        Ok(processes) => processes,
+        Err(_) => return owners,
+    };
+    for process in processes.flatten() {
+        let name = process.file_name();
+        if !name.to_string_lossy().chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        // The process may exit at any time, or belong to another user.
+        let descriptors = match fs::read_dir(process.path().join("fd")) {
+            Ok(descriptors) => descriptors,
+            Err(_) => continue,
+        };
+        let app = match fs::read_to_string(process.path().join("comm")) {
+            Ok(comm) => comm.trim().to_string(),
+            Err(_) => continue,
+        };
+        for descriptor in descriptors.flatten() {
+            if let Some(inode) = fs::read_link(descriptor.path())
+                .ok()
+                .and_then(|target| socket_inode(&target.to_string_lossy()))
+            {
+                owners.entry(inode).or_insert(app.clone());
+            }
+        }
+    }
+    owners
+}
+
+/// Reads the inode of a file descriptor link like `socket:[4242]`.
+fn socket_inode(target: &str) -> Option<u64> {
+    target
+        .strip_prefix("socket:[")?
+        .strip_suffix(']')?
+        .parse::<u64>()
+        .ok()
+}
+
+#[cfg(test)]
+mod test_proc_net {
+    use super::*;
+    use crate::test_util::listen_port;
+
+    fn fixtures() -> &'static Path {
+        Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src/fixtures/proc"))
+    }
+
+    #[test]
+    fn parse_ipv4_address() {
+        assert_eq!(
+            Some((IpAddr::V4(Ipv4Addr::LOCALHOST), 8080)),
+            parse_address("0100007F:1F90")
+        );
+        assert_eq!(None, parse_address("0100007F"));
+        assert_eq!(None, parse_address("0100007:1F90"));
+    }
+
+    #[test]
+    fn parse_ipv6_address() {
+        assert_eq!(
+            Some((IpAddr::V6(Ipv6Addr::LOCALHOST), 5432)),
+            parse_address("00000000000000000000000001000000:1538")
+        );
+        assert_eq!(
+            Some((IpAddr::V6(Ipv6Addr::UNSPECIFIED), 3000)),
+            parse_address("00000000000000000000000000000000:0BB8")
+        );
+    }
+
+    #[test]
+    fn only_listening_sockets() {
+        let content = fs::read_to_string(fixtures().join("net/tcp")).unwrap();
+        let sockets = parse_socket_table(&content, Protocol::TCP);
+        assert_eq!(
+            vec![8080, 3306],
+            sockets.iter().map(|s| s.port).collect::<Vec<u16>>()
+        );
+        let content = fs::read_to_string(fixtures().join("net/udp")).unwrap();
+        let sockets = parse_socket_table(&content, Protocol::UDP);
+        assert_eq!(
+            vec![53],
+            sockets.iter().map(|s| s.port).collect::<Vec<u16>>()
+        );
+    }
+
+    #[test]
+    fn socket_inode_of_link() {
+        assert_eq!(Some(4242), socket_inode("socket:[4242]"));
+        assert_eq!(None, socket_inode("pipe:[4242]"));
+        assert_eq!(None, socket_inode("/dev/null"));
+    }
+
+    #[test]
+    fn scan_fixture() {
+        let mut ports = scan_proc_net(fixtures());
+        ports.sort_by_key(|port| port.port);
+        assert_eq!(
+            vec![
+                listen_port(53, Protocol::UDP, "localhost", "dnsmasq"),
+                listen_port(3000, Protocol::TCP, "localhost", "node"),
+                listen_port(3306, Protocol::TCP, "127.0.0.1", "Unknown"),
+                listen_port(5353, Protocol::UDP, "localhost", "dnsmasq"),
+                listen_port(5432, Protocol::TCP, "[::1]", "postgres"),
+                listen_port(8080, Protocol::TCP, "localhost", "node"),
+            ],
+            ports
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use crate::{ListenPort, PortAttributes, Protocol};
+use std::net::SocketAddr;
+use std::str;
+
+/// The body of a **CREATE TCP** or **CREATE UDP** message.
+///
+/// Like the HELLO, the body consists of `key=value` lines and unknown keys are ignored.
+/// Containers of autoForward 0.0.5 and older send the name of the app as the whole body.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PortRequest {
+    pub app: String,
+    pub label: Option<String>,
+    pub require_local_port: bool,
+    pub elevate_if_needed: bool,
+    /// The host port asked for, the port is refused if it is taken.
+    pub host_port: Option<u16>,
+}
+
+impl Default for PortRequest {
+    fn default() -> PortRequest {
+        PortRequest {
+            app: "Unknown".to_string(),
+            label: None,
+            require_local_port: false,
+            elevate_if_needed: false,
+            host_port: None,
+        }
+    }
+}
+
+impl PortRequest {
+    pub fn new(port: &ListenPort, attributes: &PortAttributes) -> PortRequest {
+        PortRequest {
+            app: port.app.clone(),
+            label: attributes.label.clone(),
+            require_local_port: attributes.require_local_port,
+            elevate_if_needed: attributes.elevate_if_needed,
+            host_port: None,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = format!("app={}\n", self.app);
+        if let Some(label) = &self.label {
+            body.push_str(&format!("label={label}\n"));
+        }
+        if self.require_local_port {
+            body.push_str("require_local_port=true\n");
+        }
+        if self.elevate_if_needed {
+            body.push_str("elevate_if_needed=true\n");
+        }
+        if let Some(host_port) = self.host_port {
+            body.push_str(&format!("host_port={host_port}\n"));
+        }
+        body.into_bytes()
+    }
+
+    pub fn decode(body: &[u8]) -> PortRequest {
+        let mut request = PortRequest::default();
+        let body = match str::from_utf8(body) {
+            Ok(body) => body,
+            Err(_) => return request,
+        };
+        if !body.contains('=') {
+            if !body.is_empty() {
+                request.app = body.to_string();
+            }
+            return request;
+        }
+        for line in body.lines() {
+            let (key, value) = match line.split_once('=') {
+                Some(entry) => entry,
+                None => continue,
+            };
+            match key {
+                "app" => request.app = value.to_string(),
+                "label" => request.label = Some(value.to_string()),
+                "require_local_port" => request.require_local_port = value == "true",
+                "elevate_if_needed" => request.elevate_if_needed = value == "true",
+                "host_port" => request.host_port = value.parse().ok(),
+                _ => {}
+            }
+        }
+        request
+    }
+
+    /// Completes the request with attributes configured on the Host.
+    pub fn merge(mut self, attributes: &PortAttributes) -> PortRequest {
+        self.label = self.label.or(attributes.label.clone());
+        self.require_local_port |= attributes.require_local_port;
+        self.elevate_if_needed |= attributes.elevate_if_needed;
+        self
+    }
+}
+
+/// The body of a **CREATED TCP** or **CREATED UDP** message, the answer of the Host to a request.
+///
+/// The body consists of `key=value` lines, every line of the reason is sent as its own `reason`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum PortReply {
+    /// Address of the listener on the Host.
+    Forwarded(SocketAddr),
+    /// The reason why the port is not forwarded.
+    Refused(String),
+}
+
+impl PortReply {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            PortReply::Forwarded(address) => format!("status=forwarded\naddress={address}\n"),
+            PortReply::Refused(reason) => {
+                let mut body = "status=refused\n".to_string();
+                for line in reason.lines() {
+                    body.push_str(&format!("reason={line}\n"));
+                }
+                body
+            }
+        }
+        .into_bytes()
+    }
+
+    pub fn decode(body: &[u8]) -> Result<PortReply, String> {
+        let body = str::from_utf8(body).map_err(|_| "ERROR: CREATED is not valid UTF-8")?;
+        let (mut status, mut address, mut reason) = (None, None, Vec::new());
+        for line in body.lines() {
+            match line.split_once('=') {
+                Some(("status", value)) => status = Some(value),
+                Some(("address", value)) => address = value.parse::<SocketAddr>().ok(),
+                Some(("reason", value)) => reason.push(value),
+                _ => {}
+            }
+        }
+        match (status, address) {
+            (Some("forwarded"), Some(address)) => Ok(PortReply::Forwarded(address)),
+            (Some("refused"), _) => Ok(PortReply::Refused(reason.join("\n"))),
+            _ => Err(format!("ERROR: CREATED without a valid status\n{body}")),
+        }
+    }
+}
+
+/// A port to forward, given on the command line like `8080`, `8080:9090` or `53/udp`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct PortSpec {
+    pub port: u16,
+    /// The host port, otherwise it is picked by the port policy of the Host.
+    pub host_port: Option<u16>,
+    pub protocol: Protocol,
+}
+
+impl PortSpec {
+    pub fn parse(spec: &str) -> Result<PortSpec, String> {
+        let invalid = || format!("ERROR: {spec} is no port like 8080, 8080:9090 or 53/udp");
+        let (ports, protocol) = match spec.split_once('/') {
+            Some((ports, protocol)) => (ports, Protocol::decode(protocol)?),
+            None => (spec, Protocol::TCP),
+        };
+        let (port, host_port) = match ports.split_once(':') {
+            Some((port, host_port)) => (port, Some(host_port.parse().map_err(|_| invalid())?)),
+            None => (ports, None),
+        };
+        Ok(PortSpec {
+            port: port.parse().map_err(|_| invalid())?,
+            host_port,
+            protocol,
+        })
+    }
+}
+
+/// Parses the `reverse` setting into pairs of the port in the Container and the port of the Host.
+pub fn reverse_ports(specs: &[String]) -> Result<Vec<(u16, u16)>, String> {
+    specs
+        .iter()
+        .map(|spec| match PortSpec::parse(spec)? {
+            PortSpec {
+                port,
+                host_port,
+                protocol: Protocol::TCP,
+            } => Ok((port, host_port.unwrap_or(port))),
+            _ => Err(format!(
+                "ERROR: {spec} can not be reversed, only TCP is supported"
+            )),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test_request {
+    use super::*;
+
+    #[test]
+    fn ensure_inverse() {
+        let request = PortRequest {
+            app: "node".to_string(),
+            label: Some("Frontend".to_string()),
+            require_local_port: true,
+            elevate_if_needed: false,
+            host_port: Some(8080),
+        };
+        assert_eq!(request, PortRequest::decode(&request.encode()));
+    }
+
+    #[test]
+    fn decode_legacy_body() {
+        assert_eq!("node", PortRequest::decode(b"node").app);
+        assert_eq!("Unknown", PortRequest::decode(b"").app);
+        assert_eq!("Unknown", PortRequest::decode(&[0xff, 0xfe]).app);
+        let request = PortRequest::decode(b"app=node\ncolour=red\nlabel=Web");
+        assert_eq!(Some("Web".to_string()), request.label);
+    }
+
+    #[test]
+    fn ensure_inverse_reply() {
+        let forwarded = PortReply::Forwarded("127.0.0.1:3001".parse().unwrap());
+        assert_eq!(forwarded, PortReply::decode(&forwarded.encode()).unwrap());
+        let refused =
+            PortReply::Refused("ERROR: Host Port 80 is privileged\nos error 13".to_string());
+        assert_eq!(refused, PortReply::decode(&refused.encode()).unwrap());
+        assert!(PortReply::decode(b"status=forwarded\naddress=localhost").is_err());
+        assert!(PortReply::decode(b"status=unknown").is_err());
+    }
+
+    #[test]
+    fn merge_host_attributes() {
+        let attributes = PortAttributes {
+            label: Some("Host".to_string()),
+            require_local_port: true,
+            ..PortAttributes::default()
+        };
+        let request = PortRequest::decode(b"app=node\nlabel=Container").merge(&attributes);
+        assert_eq!(Some("Container".to_string()), request.label);
+        assert!(request.require_local_port);
+        assert!(!request.elevate_if_needed);
+    }
+
+    #[test]
+    fn parse_port_spec() {
+        let spec = PortSpec::parse("5353:53/udp").unwrap();
+        assert_eq!(
+            (5353, Some(53), Protocol::UDP),
+            (spec.port, spec.host_port, spec.protocol)
+        );
+        assert_eq!(None, PortSpec::parse("8080").unwrap().host_port);
+        assert!(PortSpec::parse("8080:").is_err());
+        assert!(PortSpec::parse("http").is_err());
+        assert!(PortSpec::parse("53/sctp").is_err());
+    }
+
+    #[test]
+    fn parse_reverse_ports() {
+        let specs = ["5432".to_string(), "2222:22".to_string()];
+        assert_eq!(Ok(vec![(5432, 5432), (2222, 22)]), reverse_ports(&specs));
+        assert!(reverse_ports(&["53/udp".to_string()]).is_err());
+    }
+}
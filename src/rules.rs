@@ -0,0 +1,265 @@
+use crate::{ListenPort, Protocol};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// Rules evaluated after the rules of the user, for ports which are never worth forwarding.
+pub const DEFAULT_DENY: [&str; 7] = [
+    // SSH, e.g. of the devcontainer CLI or an sshd feature.
+    "deny 22/tcp",
+    // rpcbind of NFS clients.
+    "deny 111",
+    // The embedded DNS server of Docker, listening on random ports.
+    "deny addr=127.0.0.11",
+    // Multicast DNS and LLMNR responders.
+    "deny 5353/udp",
+    "deny 5355/udp",
+    // Docker daemons of docker-in-docker setups.
+    "deny 2375-2376/tcp",
+    // The sockets of this agent.
+    "deny app=container",
+];
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// Matches ports by all of its conditions, a rule without conditions matches every port.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Rule {
+    pub action: Action,
+    pub ports: Option<RangeInclusive<u16>>,
+    pub protocol: Option<Protocol>,
+    /// Bind address of the socket, `*` matches sockets listening on every interface.
+    pub address: Option<String>,
+    /// Name of the process, a trailing `*` matches every name with that prefix.
+    pub app: Option<String>,
+}
+
+impl Rule {
+    /// Parses a rule like `deny 5000-5999/udp app=java* addr=127.0.0.1`.
+    ///
+    /// A port or range may be followed by `/tcp` or `/udp`, or the protocol is given on its own.
+    pub fn parse(rule: &str) -> Result<Rule, String> {
+        let mut tokens = rule.split_whitespace();
+        let action = match tokens.next() {
+            Some("allow") => Action::Allow,
+            Some("deny") => Action::Deny,
+            _ => {
+                return Err(format!(
+                    "ERROR: Rule \"{rule}\" has to start with allow or deny"
+                ))
+            }
+        };
+        let mut parsed = Rule {
+            action,
+            ports: None,
+            protocol: None,
+            address: None,
+            app: None,
+        };
+        for token in tokens {
+            if let Some(address) = token.strip_prefix("addr=") {
+                parsed.address = Some(address.trim_matches(['[', ']']).to_string());
+            } else if let Some(app) = token.strip_prefix("app=") {
+                parsed.app = Some(app.to_string());
+            } else if let Ok(protocol) = Protocol::decode(token) {
+                parsed.protocol = Some(protocol);
+            } else {
+                let (ports, protocol) = match token.split_once('/') {
+                    Some((ports, protocol)) => (ports, Some(Protocol::decode(protocol)?)),
+                    None => (token, None),
+                };
+                parsed.ports = Some(parse_range(ports).ok_or(format!(
+                    "ERROR: \"{token}\" of Rule \"{rule}\" is no port, range, protocol, addr= or app="
+                ))?);
+                parsed.protocol = protocol.or(parsed.protocol);
+            }
+        }
+        Ok(parsed)
+    }
+
+    pub fn matches(&self, port: &ListenPort) -> bool {
+        if let Some(ports) = &self.ports {
+            if !ports.contains(&port.port) {
+                return false;
+            }
+        }
+        if let Some(protocol) = &self.protocol {
+            if *protocol != port.protocol {
+                return false;
+            }
+        }
+        if let Some(address) = &self.address {
+            let ip = port.ip.trim_matches(['[', ']']);
+            // Sockets listening on every interface are reported as localhost.
+            if !(ip == address || address == "*" && ip == "localhost") {
+                return false;
+            }
+        }
+        if let Some(app) = &self.app {
+            let matches = match app.strip_suffix('*') {
+                Some(prefix) => port.app.starts_with(prefix),
+                None => port.app == *app,
+            };
+            if !matches {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.action {
+            Action::Allow => write!(f, "allow")?,
+            Action::Deny => write!(f, "deny")?,
+        }
+        match (&self.ports, &self.protocol) {
+            (Some(ports), protocol) => {
+                match ports.start() == ports.end() {
+                    true => write!(f, " {}", ports.start())?,
+                    false => write!(f, " {}-{}", ports.start(), ports.end())?,
+                }
+                if let Some(protocol) = protocol {
+                    write!(f, "/{}", format!("{protocol:?}").to_lowercase())?;
+                }
+            }
+            (None, Some(protocol)) => write!(f, " {}", format!("{protocol:?}").to_lowercase())?,
+            (None, None) => {}
+        }
+        if let Some(address) = &self.address {
+            write!(f, " addr={address}")?;
+        }
+        if let Some(app) = &self.app {
+            write!(f, " app={app}")?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn parse_range(ports: &str) -> Option<RangeInclusive<u16>> {
+    let (start, end) = ports.split_once('-').unwrap_or((ports, ports));
+    let (start, end) = (start.parse::<u16>().ok()?, end.parse::<u16>().ok()?);
+    match start <= end {
+        true => Some(start..=end),
+        false => None,
+    }
+}
+
+/// Decides which ports are forwarded, the first matching rule wins.
+///
+/// The rules of the user are followed by `DEFAULT_DENY`, a port matching no rule at all is
+/// forwarded.
+#[derive(Debug, Clone)]
+pub struct Rules {
+    rules: Vec<Rule>,
+}
+
+impl Rules {
+    pub fn new(mut rules: Vec<Rule>) -> Rules {
+        rules.extend(
+            DEFAULT_DENY
+                .iter()
+                .map(|rule| Rule::parse(rule).expect("Invalid default rule")),
+        );
+        Rules { rules }
+    }
+
+    /// Parses a comma separated list of rules, e.g. `allow 22/tcp, deny 9000-9999`.
+    pub fn parse(list: &str) -> Result<Rules, String> {
+        let rules = list
+            .split(',')
+            .map(|rule| rule.trim())
+            .filter(|rule| !rule.is_empty())
+            .map(Rule::parse)
+            .collect::<Result<Vec<Rule>, String>>()?;
+        Ok(Rules::new(rules))
+    }
+
+    /// The first rule matching `port`, `None` if no rule matches.
+    pub fn rule(&self, port: &ListenPort) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matches(port))
+    }
+
+    pub fn allows(&self, port: &ListenPort) -> bool {
+        match self.rule(port) {
+            Some(rule) => rule.action == Action::Allow,
+            None => true,
+        }
+    }
+}
+
+impl Default for Rules {
+    fn default() -> Rules {
+        Rules::new(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod test_rules {
+    use super::*;
+    use crate::test_util::listen_port;
+
+    #[test]
+    fn parse_rule() {
+        let rule = Rule::parse("deny 5000-5999/udp app=java* addr=[::1]").unwrap();
+        assert_eq!(
+            Rule {
+                action: Action::Deny,
+                ports: Some(5000..=5999),
+                protocol: Some(Protocol::UDP),
+                address: Some("::1".to_string()),
+                app: Some("java*".to_string()),
+            },
+            rule
+        );
+        assert_eq!("deny 5000-5999/udp addr=::1 app=java*", rule.to_string());
+        assert_eq!("allow tcp", Rule::parse("allow tcp").unwrap().to_string());
+        assert!(Rule::parse("forward 80").is_err());
+        assert!(Rule::parse("deny 90-80").is_err());
+        assert!(Rule::parse("deny 80/sctp").is_err());
+        assert!(Rule::parse("deny port=80").is_err());
+    }
+
+    #[test]
+    fn rule_conditions() {
+        let web = listen_port(3000, Protocol::TCP, "localhost", "node");
+        let debugger = listen_port(9229, Protocol::TCP, "127.0.0.1", "node");
+        let matches = |rule: &str, port: &ListenPort| Rule::parse(rule).unwrap().matches(port);
+        assert!(matches("deny", &web));
+        assert!(matches("deny 3000", &web));
+        assert!(matches("deny 2000-4000/tcp", &web));
+        assert!(!matches("deny 3000/udp", &web));
+        assert!(matches("deny addr=*", &web));
+        assert!(!matches("deny addr=*", &debugger));
+        assert!(matches("deny addr=127.0.0.1 app=no*", &debugger));
+        assert!(!matches("deny app=no", &debugger));
+        assert!(matches(
+            "deny addr=::1",
+            &listen_port(5432, Protocol::TCP, "[::1]", "postgres")
+        ));
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let rules = Rules::parse("allow 9229 app=node, deny 9000-9999, allow 22").unwrap();
+        assert!(rules.allows(&listen_port(9229, Protocol::TCP, "localhost", "node")));
+        assert!(!rules.allows(&listen_port(9230, Protocol::TCP, "localhost", "node")));
+        assert!(rules.allows(&listen_port(3000, Protocol::TCP, "localhost", "node")));
+        // Rules of the user come before the default deny list.
+        assert!(rules.allows(&listen_port(22, Protocol::TCP, "localhost", "sshd")));
+        assert!(!Rules::default().allows(&listen_port(22, Protocol::TCP, "localhost", "sshd")));
+    }
+
+    #[test]
+    fn default_deny() {
+        let rules = Rules::default();
+        assert!(!rules.allows(&listen_port(41235, Protocol::UDP, "127.0.0.11", "dockerd")));
+        assert!(!rules.allows(&listen_port(5353, Protocol::UDP, "localhost", "avahi")));
+        assert!(rules.allows(&listen_port(5353, Protocol::TCP, "localhost", "app")));
+        assert!(!rules.allows(&listen_port(40000, Protocol::TCP, "localhost", "container")));
+    }
+}
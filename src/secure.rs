@@ -0,0 +1,275 @@
+use crate::Transport;
+use sha2::{Digest, Sha256};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Noise pattern of the tunnel, both sides are authenticated by the token as pre-shared key.
+const NOISE_PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_SHA256";
+
+/// Largest Noise message, every message is sent with its length as two bytes in front.
+const MAX_NOISE_MESSAGE: usize = 65535;
+
+/// Authentication tag added to every encrypted message.
+const TAG_SIZE: usize = 16;
+
+/// Largest payload of a single encrypted message.
+const MAX_PAYLOAD: usize = MAX_NOISE_MESSAGE - TAG_SIZE;
+
+/// Decrypted data of the last message, which was not read yet.
+struct Received {
+    nonce: u64,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+/// A `Transport` encrypted with the Noise protocol, keyed with the token of the Host.
+///
+/// Clones share the keys and nonces, so one clone can read while another one writes.
+pub struct SecureStream<T: Transport> {
+    stream: T,
+    noise: Arc<StatelessTransportState>,
+    /// Nonce of the next message, locked for the whole write so messages of clones never mix.
+    sending: Arc<Mutex<u64>>,
+    receiving: Arc<Mutex<Received>>,
+}
+
+impl<T: Transport> SecureStream<T> {
+    /// Container side, starts the handshake.
+    pub fn connect(mut stream: T, token: &str) -> Result<SecureStream<T>, String> {
+        let mut noise = handshake_state(token, true)?;
+        send_handshake(&mut stream, &mut noise)?;
+        receive_handshake(&mut stream, &mut noise)?;
+        SecureStream::established(stream, noise)
+    }
+
+    /// Host side, answers the handshake of the Container.
+    pub fn accept(mut stream: T, token: &str) -> Result<SecureStream<T>, String> {
+        let mut noise = handshake_state(token, false)?;
+        receive_handshake(&mut stream, &mut noise)?;
+        send_handshake(&mut stream, &mut noise)?;
+        SecureStream::established(stream, noise)
+    }
+
+    fn established(stream: T, noise: HandshakeState) -> Result<SecureStream<T>, String> {
+        let noise = noise
+            .into_stateless_transport_mode()
+            .map_err(|err| format!("ERROR: Unable to finish the encryption\n{err}"))?;
+        Ok(SecureStream {
+            stream,
+            noise: Arc::new(noise),
+            sending: Arc::new(Mutex::new(0)),
+            receiving: Arc::new(Mutex::new(Received {
+                nonce: 0,
+                buffer: Vec::new(),
+                position: 0,
+            })),
+        })
+    }
+}
+
+impl<T: Transport> Transport for SecureStream<T> {
+    fn try_clone(&self) -> io::Result<SecureStream<T>> {
+        Ok(SecureStream {
+            stream: self.stream.try_clone()?,
+            noise: self.noise.clone(),
+            sending: self.sending.clone(),
+            receiving: self.receiving.clone(),
+        })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        self.stream.shutdown(how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.stream.set_nodelay(nodelay)
+    }
+
+    fn peer(&self) -> String {
+        self.stream.peer()
+    }
+}
+
+fn handshake_state(token: &str, initiator: bool) -> Result<HandshakeState, String> {
+    // The pre-shared key has to be 32 bytes, whatever the length of the token is.
+    let psk = Sha256::digest(token.as_bytes());
+    let builder = Builder::new(NOISE_PARAMS.parse().expect("Valid Noise parameters")).psk(0, &psk);
+    match initiator {
+        true => builder.build_initiator(),
+        false => builder.build_responder(),
+    }
+    .map_err(|err| format!("ERROR: Unable to start the encryption\n{err}"))
+}
+
+fn send_handshake<W: Write>(stream: &mut W, noise: &mut HandshakeState) -> Result<(), String> {
+    let mut message = vec![0; MAX_NOISE_MESSAGE];
+    let size = noise
+        .write_message(&[], &mut message)
+        .map_err(|err| format!("ERROR: Unable to encrypt the Session\n{err}"))?;
+    write_frame(stream, &message[..size])
+        .map_err(|err| format!("ERROR: Unable to encrypt the Session\n{err}"))
+}
+
+fn receive_handshake<R: Read>(stream: &mut R, noise: &mut HandshakeState) -> Result<(), String> {
+    let message = match read_frame(stream) {
+        Ok(Some(message)) if !message.is_empty() => message,
+        // The size of a plain HELLO starts with two zero bytes.
+        Ok(Some(_)) => {
+            return Err(
+                "ERROR: The peer does not encrypt the Session, set encrypt on both sides"
+                    .to_string(),
+            )
+        }
+        Ok(None) => {
+            return Err(
+                "ERROR: The peer closed the connection, does it encrypt the Session as well?"
+                    .to_string(),
+            )
+        }
+        Err(err) => return Err(format!("ERROR: Unable to encrypt the Session\n{err}")),
+    };
+    let mut payload = vec![0; MAX_NOISE_MESSAGE];
+    noise.read_message(&message, &mut payload).map_err(|_| {
+        "ERROR: Unable to encrypt the Session, the peer does not know the token of the Host"
+            .to_string()
+    })?;
+    Ok(())
+}
+
+fn write_frame<W: Write>(stream: &mut W, message: &[u8]) -> io::Result<()> {
+    let mut frame = Vec::with_capacity(2 + message.len());
+    frame.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    frame.extend_from_slice(message);
+    stream.write_all(&frame)
+}
+
+/// The next Noise message, `None` if the connection was closed before it.
+fn read_frame<R: Read>(stream: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut size = [0; 2];
+    match stream.read(&mut size[..1]) {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        Err(err) => return Err(err),
+    }
+    stream.read_exact(&mut size[1..])?;
+    let mut message = vec![0; u16::from_be_bytes(size) as usize];
+    stream.read_exact(&mut message)?;
+    Ok(Some(message))
+}
+
+impl<T: Transport> Read for SecureStream<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut received = self.receiving.lock().unwrap();
+        while received.position == received.buffer.len() {
+            let message = match read_frame(&mut self.stream)? {
+                Some(message) => message,
+                None => return Ok(0),
+            };
+            let mut payload = vec![0; message.len()];
+            let size = self
+                .noise
+                .read_message(received.nonce, &message, &mut payload)
+                .map_err(|_| {
+                    io::Error::new(ErrorKind::InvalidData, "Unable to decrypt a message")
+                })?;
+            payload.truncate(size);
+            received.nonce += 1;
+            received.buffer = payload;
+            received.position = 0;
+        }
+        let position = received.position;
+        let size = buf.len().min(received.buffer.len() - position);
+        buf[..size].copy_from_slice(&received.buffer[position..position + size]);
+        received.position += size;
+        Ok(size)
+    }
+}
+
+impl<T: Transport> Write for SecureStream<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut nonce = self.sending.lock().unwrap();
+        let mut message = vec![0; MAX_NOISE_MESSAGE];
+        for chunk in buf.chunks(MAX_PAYLOAD) {
+            let size = self
+                .noise
+                .write_message(*nonce, chunk, &mut message)
+                .map_err(|err| io::Error::new(ErrorKind::InvalidInput, err.to_string()))?;
+            *nonce += 1;
+            write_frame(&mut self.stream, &message[..size])?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod test_secure {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    fn pair(
+        host_token: &'static str,
+        container_token: &str,
+    ) -> (
+        Result<SecureStream<TcpStream>, String>,
+        Result<SecureStream<TcpStream>, String>,
+    ) {
+        let host = TcpListener::bind("127.0.0.1:0").unwrap();
+        let container = TcpStream::connect(host.local_addr().unwrap()).unwrap();
+        let accepted = thread::spawn(move || {
+            let (stream, _) = host.accept().unwrap();
+            SecureStream::accept(stream, host_token)
+        });
+        let connected = SecureStream::connect(container, container_token);
+        (connected, accepted.join().unwrap())
+    }
+
+    #[test]
+    fn encrypted_in_both_directions() {
+        let (container, host) = pair("secret", "secret");
+        let (mut container, mut host) = (container.unwrap(), host.unwrap());
+        // Larger than a single Noise message, written and read by different clones.
+        let data = (0..200_000).map(|i| i as u8).collect::<Vec<u8>>();
+        let mut writer = container.try_clone().unwrap();
+        let sent = data.clone();
+        let sending = thread::spawn(move || writer.write_all(&sent).unwrap());
+        let mut received = vec![0; data.len()];
+        host.read_exact(&mut received).unwrap();
+        sending.join().unwrap();
+        assert_eq!(data, received);
+
+        host.write_all(b"pong").unwrap();
+        host.shutdown(Shutdown::Write).unwrap();
+        let mut answer = Vec::new();
+        container.read_to_end(&mut answer).unwrap();
+        assert_eq!(b"pong".to_vec(), answer);
+    }
+
+    #[test]
+    fn wrong_token() {
+        let (container, host) = pair("secret", "guess");
+        assert!(container.is_err());
+        assert!(host.err().unwrap().contains("token"));
+    }
+
+    #[test]
+    fn plain_peer() {
+        let host = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut container = TcpStream::connect(host.local_addr().unwrap()).unwrap();
+        let (stream, _) = host.accept().unwrap();
+        container.write_all(&[0, 0, 0, 4, 0b1000_0001]).unwrap();
+        let err = SecureStream::accept(stream, "secret").err().unwrap();
+        assert!(err.contains("does not encrypt"));
+    }
+}
@@ -0,0 +1,23 @@
+//! Fixtures shared by the tests of several modules.
+
+use crate::{ListenPort, Protocol};
+use std::net::TcpListener;
+
+/// A listening socket of `app`, as a `PortDetector` reports it.
+pub(crate) fn listen_port(port: u16, protocol: Protocol, ip: &str, app: &str) -> ListenPort {
+    ListenPort {
+        port,
+        ip: ip.to_string(),
+        protocol,
+        app: app.to_string(),
+    }
+}
+
+/// A port nobody listens on right now, which may be taken again before it is used.
+pub(crate) fn free_port() -> u16 {
+    TcpListener::bind("localhost:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
@@ -0,0 +1,248 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// A connection a Session runs over, like TCP, a Unix socket, pipes or an encrypted `Tunnel`.
+///
+/// The Multiplexer reads from one clone while another one writes, so clones have to share the
+/// same connection.
+pub trait Transport: Read + Write + Send + Sized + 'static {
+    /// A second handle of the same connection.
+    fn try_clone(&self) -> io::Result<Self>;
+
+    /// Closes the connection, `Shutdown::Write` tells the other side that nothing follows.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    /// Reads fail after `timeout`, transports without timeouts ignore it.
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Sends small frames right away, instead of waiting for more data.
+    fn set_nodelay(&self, _nodelay: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The other end of the connection, for the logs.
+    fn peer(&self) -> String {
+        "unknown address".to_string()
+    }
+}
+
+impl Transport for TcpStream {
+    fn try_clone(&self) -> io::Result<TcpStream> {
+        TcpStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+
+    fn peer(&self) -> String {
+        match self.peer_addr() {
+            Ok(addr) => addr.to_string(),
+            Err(_) => "unknown address".to_string(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn try_clone(&self) -> io::Result<UnixStream> {
+        UnixStream::try_clone(self)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn peer(&self) -> String {
+        "the Unix socket".to_string()
+    }
+}
+
+#[cfg(test)]
+mod test_transport {
+    use super::*;
+    use crate::{
+        accept_hello_with_token, create_message, read_message, send_hello, send_hello_with_token,
+        send_message, Function, Hello, HostPorts, Multiplexer, PortPolicy, PortReply, SecureStream,
+    };
+    use std::collections::{HashMap, VecDeque};
+    use std::io::ErrorKind;
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::thread;
+
+    /// Bytes written to one end of a `Memory` connection, until the writer shuts down.
+    #[derive(Default)]
+    struct Buffer {
+        data: VecDeque<u8>,
+        closed: bool,
+    }
+
+    type Shared = Arc<(Mutex<Buffer>, Condvar)>;
+
+    /// One end of an in-memory connection, see `duplex`.
+    struct Memory {
+        incoming: Shared,
+        outgoing: Shared,
+    }
+
+    fn duplex() -> (Memory, Memory) {
+        let (first, second) = (Shared::default(), Shared::default());
+        let container = Memory {
+            incoming: first.clone(),
+            outgoing: second.clone(),
+        };
+        let host = Memory {
+            incoming: second,
+            outgoing: first,
+        };
+        (container, host)
+    }
+
+    fn close(buffer: &Shared) {
+        buffer.0.lock().unwrap().closed = true;
+        buffer.1.notify_all();
+    }
+
+    impl Read for Memory {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let (buffer, ready) = &*self.incoming;
+            let mut buffer = buffer.lock().unwrap();
+            while buffer.data.is_empty() && !buffer.closed {
+                buffer = ready.wait(buffer).unwrap();
+            }
+            let size = buf.len().min(buffer.data.len());
+            for (target, byte) in buf.iter_mut().zip(buffer.data.drain(..size)) {
+                *target = byte;
+            }
+            Ok(size)
+        }
+    }
+
+    impl Write for Memory {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let (buffer, ready) = &*self.outgoing;
+            let mut buffer = buffer.lock().unwrap();
+            if buffer.closed {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            buffer.data.extend(buf);
+            ready.notify_all();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Memory {
+        fn try_clone(&self) -> io::Result<Memory> {
+            Ok(Memory {
+                incoming: self.incoming.clone(),
+                outgoing: self.outgoing.clone(),
+            })
+        }
+
+        fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+            if how != Shutdown::Read {
+                close(&self.outgoing);
+            }
+            if how != Shutdown::Write {
+                close(&self.incoming);
+            }
+            Ok(())
+        }
+    }
+
+    /// The reply of the Host to forwarding `port`.
+    fn create_tcp<T: Transport>(container: &mut T, port: u16) -> PortReply {
+        let request = create_message(port, Function::CreateTcp, b"test".to_vec());
+        send_message(container, request).unwrap();
+        let created = read_message(container).unwrap().unwrap();
+        assert_eq!(Function::CreatedTcp, created.header.function);
+        PortReply::decode(&created.body).unwrap()
+    }
+
+    #[test]
+    fn session_in_memory() {
+        // Only the forwarded ports are sockets, the Session stays in memory.
+        let listener = std::net::TcpListener::bind("localhost:0").unwrap();
+        let taken = listener.local_addr().unwrap().port();
+        let table = HashMap::from([(4321, 0), (taken, taken)]);
+        let host_ports = HostPorts::new().with_policy(PortPolicy::Fixed(table));
+        let (mut container, host) = duplex();
+        let run = thread::spawn(move || Multiplexer::with_host_ports(host, host_ports).run());
+        let agreed = send_hello(&mut container, &Hello::new("test".to_string(), None)).unwrap();
+        assert!(agreed.supports("tcp"));
+
+        assert!(matches!(
+            create_tcp(&mut container, 4321),
+            PortReply::Forwarded(address) if address.port() != 0
+        ));
+        assert!(matches!(
+            create_tcp(&mut container, taken),
+            PortReply::Refused(_)
+        ));
+
+        drop(listener);
+
+        send_message(
+            &mut container,
+            create_message(0, Function::Close, Vec::new()),
+        )
+        .unwrap();
+        run.join().unwrap();
+    }
+
+    #[test]
+    fn authenticate_in_memory() {
+        let (mut container, mut host) = duplex();
+        let accepted = thread::spawn(move || {
+            let hello = Hello::new("host".to_string(), None);
+            accept_hello_with_token(&mut host, &hello, Some("secret"))
+        });
+        let hello = Hello::new("test".to_string(), None);
+        let host = send_hello_with_token(&mut container, &hello, Some("secret")).unwrap();
+        assert_eq!("host", host.hostname);
+        assert_eq!("test", accepted.join().unwrap().unwrap().hostname);
+
+        container.shutdown(Shutdown::Write).unwrap();
+        assert!(container.write_all(b"late").is_err());
+    }
+
+    #[test]
+    fn encrypted_in_memory() {
+        let (container, host) = duplex();
+        let run = thread::spawn(move || {
+            let host = SecureStream::accept(host, "secret").unwrap();
+            Multiplexer::new(host).run()
+        });
+        let mut container = SecureStream::connect(container, "secret").unwrap();
+        let agreed = send_hello(&mut container, &Hello::new("test".to_string(), None)).unwrap();
+        assert!(agreed.supports("tcp"));
+
+        send_message(
+            &mut container,
+            create_message(0, Function::Close, Vec::new()),
+        )
+        .unwrap();
+        run.join().unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+use crate::{SecureStream, Transport};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The connection between a Container and the Host, see `SecureStream` for the encrypted one.
+pub enum Tunnel {
+    Plain(TcpStream),
+    /// A socket in a directory shared with the Container, e.g. a bind mount.
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Pipe(Pipe),
+    /// Boxed, it wraps one of the other connections.
+    Secure(Box<SecureStream<Tunnel>>),
+}
+
+/// Separate halves for reading and writing, like stdin and stdout of the agent or of a command.
+///
+/// Clones share both halves, so one clone can read while another one writes.
+#[derive(Clone)]
+pub struct Pipe {
+    reader: Arc<Mutex<Box<dyn Read + Send>>>,
+    /// Dropped on a shutdown, which closes the pipe for the other end.
+    writer: Arc<Mutex<Option<Box<dyn Write + Send>>>>,
+}
+
+impl Pipe {
+    pub fn new<R, W>(reader: R, writer: W) -> Pipe
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        Pipe {
+            reader: Arc::new(Mutex::new(Box::new(reader))),
+            writer: Arc::new(Mutex::new(Some(Box::new(writer)))),
+        }
+    }
+
+    /// stdin and stdout of this Program, nothing else may be printed to stdout.
+    pub fn stdio() -> Pipe {
+        Pipe::new(io::stdin(), io::stdout())
+    }
+}
+
+impl Transport for Pipe {
+    fn try_clone(&self) -> io::Result<Pipe> {
+        Ok(self.clone())
+    }
+
+    /// Only the writing half can be closed, the reader sees the end of the other side.
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if how != Shutdown::Read {
+            self.writer.lock().unwrap().take();
+        }
+        Ok(())
+    }
+
+    fn peer(&self) -> String {
+        "a pipe".to_string()
+    }
+}
+
+impl Read for &Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.lock().unwrap().read(buf)
+    }
+}
+
+impl Write for &Pipe {
+    /// Flushed right away, stdout would hold back frames until the next newline.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.writer.lock().unwrap().as_mut() {
+            Some(writer) => writer.write(buf).and_then(|size| {
+                writer.flush()?;
+                Ok(size)
+            }),
+            None => Err(ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer.lock().unwrap().as_mut() {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl From<Pipe> for Tunnel {
+    fn from(pipe: Pipe) -> Tunnel {
+        Tunnel::Pipe(pipe)
+    }
+}
+
+impl From<TcpStream> for Tunnel {
+    fn from(stream: TcpStream) -> Tunnel {
+        Tunnel::Plain(stream)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for Tunnel {
+    fn from(stream: UnixStream) -> Tunnel {
+        Tunnel::Unix(stream)
+    }
+}
+
+impl From<SecureStream<Tunnel>> for Tunnel {
+    fn from(stream: SecureStream<Tunnel>) -> Tunnel {
+        Tunnel::Secure(Box::new(stream))
+    }
+}
+
+impl Transport for Tunnel {
+    fn try_clone(&self) -> io::Result<Tunnel> {
+        match self {
+            Tunnel::Plain(stream) => stream.try_clone().map(Tunnel::Plain),
+            #[cfg(unix)]
+            Tunnel::Unix(stream) => stream.try_clone().map(Tunnel::Unix),
+            Tunnel::Pipe(pipe) => pipe.try_clone().map(Tunnel::Pipe),
+            Tunnel::Secure(stream) => stream.try_clone().map(Tunnel::from),
+        }
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Tunnel::Plain(stream) => stream.shutdown(how),
+            #[cfg(unix)]
+            Tunnel::Unix(stream) => stream.shutdown(how),
+            Tunnel::Pipe(pipe) => pipe.shutdown(how),
+            Tunnel::Secure(stream) => stream.shutdown(how),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Tunnel::Plain(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Tunnel::Unix(stream) => stream.set_read_timeout(timeout),
+            // Pipes have no timeouts, the Host kills a spawned command which stays silent instead.
+            Tunnel::Pipe(pipe) => pipe.set_read_timeout(timeout),
+            Tunnel::Secure(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Tunnel::Plain(stream) => stream.set_nodelay(nodelay),
+            // Unix sockets do not buffer small writes in the first place.
+            #[cfg(unix)]
+            Tunnel::Unix(stream) => stream.set_nodelay(nodelay),
+            Tunnel::Pipe(pipe) => pipe.set_nodelay(nodelay),
+            Tunnel::Secure(stream) => stream.set_nodelay(nodelay),
+        }
+    }
+
+    fn peer(&self) -> String {
+        match self {
+            Tunnel::Plain(stream) => stream.peer(),
+            #[cfg(unix)]
+            Tunnel::Unix(stream) => stream.peer(),
+            Tunnel::Pipe(pipe) => pipe.peer(),
+            Tunnel::Secure(stream) => stream.peer(),
+        }
+    }
+}
+
+impl Read for Tunnel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Tunnel::Plain(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Tunnel::Unix(stream) => stream.read(buf),
+            Tunnel::Pipe(pipe) => pipe.read(buf),
+            Tunnel::Secure(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Tunnel {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Tunnel::Plain(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Tunnel::Unix(stream) => stream.write(buf),
+            Tunnel::Pipe(pipe) => pipe.write(buf),
+            Tunnel::Secure(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Tunnel::Plain(stream) => stream.flush(),
+            #[cfg(unix)]
+            Tunnel::Unix(stream) => stream.flush(),
+            Tunnel::Pipe(pipe) => pipe.flush(),
+            Tunnel::Secure(stream) => stream.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_tunnel {
+    use super::*;
+    use crate::{
+        accept_hello_with_token, create_message, send_hello, send_hello_with_token, send_message,
+        Function, Hello, Multiplexer,
+    };
+    use std::thread;
+
+    #[test]
+    fn session_over_unix_socket() {
+        let (container, host) = UnixStream::pair().unwrap();
+        let run = thread::spawn(move || Multiplexer::new(host).run());
+        let mut container = Tunnel::from(container);
+        send_hello(&mut container, &Hello::new("test".to_string(), None)).unwrap();
+
+        let mut writer = container.try_clone().unwrap();
+        send_message(&mut writer, create_message(0, Function::Close, Vec::new())).unwrap();
+        run.join().unwrap();
+    }
+
+    #[test]
+    fn session_over_pipes() {
+        let (to_host, from_container) = UnixStream::pair().unwrap();
+        let (to_container, from_host) = UnixStream::pair().unwrap();
+        let host = Pipe::new(from_container, to_container);
+        let run = thread::spawn(move || Multiplexer::new(host).run());
+        let mut container = Tunnel::from(Pipe::new(from_host, to_host));
+        send_hello(&mut container, &Hello::new("test".to_string(), None)).unwrap();
+
+        // Closing its stdin ends the Session, like a Container exiting.
+        container.shutdown(Shutdown::Write).unwrap();
+        run.join().unwrap();
+        assert!(container.write_all(b"late").is_err());
+    }
+
+    #[test]
+    fn encrypted_unix_socket() {
+        let (container, host) = UnixStream::pair().unwrap();
+        let accepted = thread::spawn(move || {
+            let mut tunnel =
+                Tunnel::from(SecureStream::accept(Tunnel::from(host), "secret").unwrap());
+            accept_hello_with_token(
+                &mut tunnel,
+                &Hello::new("host".to_string(), None),
+                Some("secret"),
+            )
+        });
+        let mut container =
+            Tunnel::from(SecureStream::connect(Tunnel::from(container), "secret").unwrap());
+        let host = send_hello_with_token(
+            &mut container,
+            &Hello::new("test".to_string(), None),
+            Some("secret"),
+        );
+        assert_eq!("host", host.unwrap().hostname);
+        assert_eq!("test", accepted.join().unwrap().unwrap().hostname);
+    }
+}